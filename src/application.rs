@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use adw::{prelude::*, subclass::prelude::*};
 use gettextrs::gettext;
 use gtk::{
@@ -8,20 +10,24 @@ use gtk::{
 use crate::{
     about,
     config::{APP_ID, PKGDATADIR, PROFILE, VERSION},
+    dbus_service::DBusService,
     format,
+    i18n::ngettext_f,
     preferences_dialog::PreferencesDialog,
+    recovery,
     settings::Settings,
     window::Window,
 };
 
 mod imp {
-    use std::cell::OnceCell;
+    use std::cell::{OnceCell, RefCell};
 
     use super::*;
 
     #[derive(Debug, Default)]
     pub struct Application {
         pub(super) settings: OnceCell<Settings>,
+        pub(super) dbus_service: RefCell<Option<DBusService>>,
     }
 
     #[glib::object_subclass]
@@ -46,6 +52,14 @@ mod imp {
 
             let window = Window::new(&obj);
             window.present();
+
+            glib::spawn_future_local(clone!(
+                #[weak]
+                obj,
+                async move {
+                    obj.offer_recovery().await;
+                }
+            ));
         }
 
         fn startup(&self) {
@@ -58,6 +72,27 @@ mod imp {
             obj.setup_gactions();
             obj.setup_accels();
         }
+
+        fn dbus_register(
+            &self,
+            connection: &gio::DBusConnection,
+            object_path: &str,
+        ) -> Result<(), glib::Error> {
+            self.parent_dbus_register(connection, object_path)?;
+
+            let dbus_service = DBusService::register(connection, object_path)?;
+            self.dbus_service.replace(Some(dbus_service));
+
+            Ok(())
+        }
+
+        fn dbus_unregister(&self, connection: &gio::DBusConnection, object_path: &str) {
+            if let Some(dbus_service) = self.dbus_service.take() {
+                dbus_service.unregister();
+            }
+
+            self.parent_dbus_unregister(connection, object_path);
+        }
     }
 
     impl GtkApplicationImpl for Application {}
@@ -106,6 +141,11 @@ impl Application {
         })
     }
 
+    /// Returns the exported D-Bus service, if the app is registered on the bus.
+    pub fn dbus_service(&self) -> Option<DBusService> {
+        self.imp().dbus_service.borrow().clone()
+    }
+
     pub fn window(&self) -> Window {
         self.active_window()
             .map_or_else(|| Window::new(self), |w| w.downcast().unwrap())
@@ -113,24 +153,44 @@ impl Application {
 
     pub async fn send_record_success_notification(
         &self,
-        recording_file: &gio::File,
+        recording_files: &[gio::File],
         duration: gst::ClockTime,
     ) {
+        let Some(recording_file) = recording_files.first() else {
+            tracing::error!("No recording files to notify about");
+            return;
+        };
+
         let mut body_fragments = vec![format::duration(duration)];
 
-        match recording_file
-            .query_info_future(
-                gio::FILE_ATTRIBUTE_STANDARD_SIZE,
-                gio::FileQueryInfoFlags::NONE,
-                glib::Priority::DEFAULT_IDLE,
-            )
-            .await
-        {
-            Ok(file_info) => {
-                let formatted_size = glib::format_size(file_info.size() as u64);
-                body_fragments.push(formatted_size.to_string());
+        if recording_files.len() > 1 {
+            body_fragments.push(ngettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "{n_files} file",
+                "{n_files} files",
+                recording_files.len() as u32,
+                &[("n_files", &recording_files.len().to_string())],
+            ));
+        }
+
+        let mut total_size = 0;
+        for file in recording_files {
+            match file
+                .query_info_future(
+                    gio::FILE_ATTRIBUTE_STANDARD_SIZE,
+                    gio::FileQueryInfoFlags::NONE,
+                    glib::Priority::DEFAULT_IDLE,
+                )
+                .await
+            {
+                Ok(file_info) => total_size += file_info.size() as u64,
+                Err(err) => tracing::warn!("Failed to get file size: {:?}", err),
             }
-            Err(err) => tracing::warn!("Failed to get file size: {:?}", err),
+        }
+
+        if total_size > 0 {
+            let formatted_size = glib::format_size(total_size);
+            body_fragments.push(formatted_size.to_string());
         }
 
         // Translators: This is a message that the user will see when the recording is finished.
@@ -149,6 +209,98 @@ impl Application {
         self.send_notification(Some("record-success"), &notification);
     }
 
+    pub fn send_post_recording_command_failure_notification(&self, err: &anyhow::Error) {
+        let notification = gio::Notification::new(&gettext("Post-recording command failed"));
+        notification.set_body(Some(&err.to_string()));
+
+        self.send_notification(Some("post-recording-command-failure"), &notification);
+    }
+
+    /// Asks whether to recover the recordings that were left incomplete by a
+    /// previous session, if there are any.
+    async fn offer_recovery(&self) {
+        const IGNORE_RESPONSE_ID: &str = "ignore";
+        const RECOVER_RESPONSE_ID: &str = "recover";
+
+        let settings = self.settings();
+
+        let paths = recovery::leftover_recordings(settings);
+        if paths.is_empty() {
+            return;
+        }
+
+        tracing::debug!(?paths, "Found incomplete recordings");
+
+        let n_paths = paths.len() as u32;
+        let dialog = adw::AlertDialog::builder()
+            .heading(gettext("Recover Incomplete Recordings?"))
+            .body(ngettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "{n_recordings} recording was not finished properly. Recovering saves a playable copy and moves the original to the trash.",
+                "{n_recordings} recordings were not finished properly. Recovering saves playable copies and moves the originals to the trash.",
+                n_paths,
+                &[("n_recordings", &n_paths.to_string())],
+            ))
+            .close_response(IGNORE_RESPONSE_ID)
+            .default_response(RECOVER_RESPONSE_ID)
+            .build();
+
+        dialog.add_response(IGNORE_RESPONSE_ID, &gettext("Ignore"));
+
+        dialog.add_response(RECOVER_RESPONSE_ID, &gettext("Recover"));
+        dialog.set_response_appearance(RECOVER_RESPONSE_ID, adw::ResponseAppearance::Suggested);
+
+        let response = dialog.choose_future(Some(&self.window())).await;
+
+        let mut recovered_paths = Vec::new();
+        for path in paths {
+            if response == RECOVER_RESPONSE_ID {
+                match recovery::recover(&path).await {
+                    Ok(recovered_path) => recovered_paths.push(recovered_path),
+                    Err(err) => {
+                        tracing::error!("Failed to recover recording: {:?}", err);
+                        continue;
+                    }
+                }
+            }
+
+            settings.remove_incomplete_recording(&path);
+        }
+
+        if response == RECOVER_RESPONSE_ID {
+            self.send_recovery_notification(&recovered_paths, n_paths);
+        }
+    }
+
+    fn send_recovery_notification(&self, recovered_paths: &[PathBuf], n_attempted: u32) {
+        let n_failed = n_attempted - recovered_paths.len() as u32;
+
+        let notification = if n_failed == 0 {
+            gio::Notification::new(&gettext("Recordings recovered"))
+        } else {
+            let notification = gio::Notification::new(&gettext("Failed to recover recordings"));
+            notification.set_body(Some(&ngettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "{n_failed} recording could not be recovered and was kept as is.",
+                "{n_failed} recordings could not be recovered and were kept as is.",
+                n_failed,
+                &[("n_failed", &n_failed.to_string())],
+            )));
+            notification
+        };
+
+        if let Some(recovered_path) = recovered_paths.first() {
+            let uri = gio::File::for_path(recovered_path).uri();
+            notification.add_button_with_target_value(
+                &gettext("Show in Files"),
+                "app.show-in-files",
+                Some(&uri.to_variant()),
+            );
+        }
+
+        self.send_notification(Some("recovery"), &notification);
+    }
+
     pub fn run(&self) -> glib::ExitCode {
         tracing::info!("Kooha ({})", APP_ID);
         tracing::info!("Version: {} ({})", VERSION, PROFILE);
@@ -253,5 +405,6 @@ impl Application {
         self.set_accels_for_action("win.toggle-record", &["<Control>r"]);
         // self.set_accels_for_action("win.toggle-pause", &["<Control>k"]); // See issue #112 in GitHub repo
         self.set_accels_for_action("win.cancel-record", &["<Control>c"]);
+        self.set_accels_for_action("win.save-replay", &["<Control>s"]);
     }
 }
@@ -0,0 +1,478 @@
+use std::{cell::RefCell, io, os::raw::c_int, path::PathBuf, rc::Rc, thread, time::Duration};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use futures_channel::oneshot;
+use futures_util::{
+    FutureExt,
+    future::{self, Either},
+};
+use gtk::{
+    gio::{self, prelude::*},
+    glib::{self, translate::*},
+};
+
+use crate::{
+    profile::Profile,
+    recording::{Recording, RecordingState},
+    settings::{CaptureMode, Settings},
+};
+
+const USAGE: &str = "\
+Usage: kooha record [OPTIONS]
+
+Records the screen without opening the main window.
+
+Options:
+  --profile <ID>        Format to record with (see --list-profiles)
+  --framerate <FPS>     Frame rate (e.g., 30, 29.97, or 30000/1001)
+  --duration <TIME>     Stop after the given time (e.g., 90, 90s, 10m, or 1h)
+  --delay <SECS>        Seconds to wait before recording begins
+  --output <PATH>       File or folder to save the recording to
+  --no-audio            Do not record desktop audio and microphone
+  --hide-pointer        Do not show the pointer in the recording
+  --list-profiles       List the ids of the available formats and exit
+  -h, --help            Show this help and exit
+
+If no duration is given, the recording stops when Enter is pressed. It is
+also stopped, and saved, on SIGINT or SIGTERM.";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    #[default]
+    Record,
+    ListProfiles,
+    ShowHelp,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct RecordArgs {
+    action: Action,
+    profile_id: Option<String>,
+    framerate: Option<gst::Fraction>,
+    duration: Option<Duration>,
+    delay: Option<u32>,
+    output: Option<PathBuf>,
+    no_audio: bool,
+    hide_pointer: bool,
+}
+
+impl RecordArgs {
+    fn parse(args: &[String]) -> Result<Self> {
+        let mut ret = Self::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .with_context(|| format!("Missing value for `{}`", arg))
+            };
+
+            match arg.as_str() {
+                "--profile" => ret.profile_id = Some(value()?.clone()),
+                "--framerate" => ret.framerate = Some(parse_framerate(value()?)?),
+                "--duration" => ret.duration = Some(parse_duration(value()?)?),
+                "--delay" => {
+                    let raw = value()?;
+                    ret.delay = Some(
+                        raw.parse()
+                            .with_context(|| format!("Invalid delay `{}`", raw))?,
+                    );
+                }
+                "--output" => ret.output = Some(PathBuf::from(value()?)),
+                "--no-audio" => ret.no_audio = true,
+                "--hide-pointer" => ret.hide_pointer = true,
+                "--list-profiles" => ret.action = Action::ListProfiles,
+                "-h" | "--help" => ret.action = Action::ShowHelp,
+                other => bail!("Unknown option `{}`", other),
+            }
+        }
+
+        Ok(ret)
+    }
+}
+
+/// Runs the `record` subcommand with the given arguments, excluding the
+/// program name and the subcommand itself.
+pub fn run(args: &[String]) -> glib::ExitCode {
+    let args = match RecordArgs::parse(args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{:#}\n\n{}", err, USAGE);
+            return glib::ExitCode::FAILURE;
+        }
+    };
+
+    match args.action {
+        Action::ShowHelp => {
+            print_stdout(USAGE);
+            glib::ExitCode::SUCCESS
+        }
+        Action::ListProfiles => match Profile::all() {
+            Ok(profiles) => {
+                for profile in profiles.iter().filter(|p| p.is_available()) {
+                    print_stdout(&format!("{}\t{}", profile.id(), profile.name()));
+                }
+                for err in Profile::user_profile_errors() {
+                    eprintln!("Skipped user profile: {}", err);
+                }
+                glib::ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("Failed to load profiles: {:?}", err);
+                glib::ExitCode::FAILURE
+            }
+        },
+        Action::Record => match glib::MainContext::default().block_on(record(args)) {
+            Ok(files) => {
+                for file in files {
+                    print_stdout(&file.path().unwrap_or_default().display().to_string());
+                }
+                glib::ExitCode::SUCCESS
+            }
+            Err(err) => {
+                eprintln!("{:?}", err);
+                glib::ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+async fn record(args: RecordArgs) -> Result<Vec<gio::File>> {
+    let settings = Settings::default();
+
+    // Overrides must not leak to the settings used by the app.
+    settings.delay();
+    apply_overrides(&settings, &args)?;
+
+    let recording = Recording::new();
+
+    if let Some(ref output) = args.output
+        && !output.is_dir()
+    {
+        recording.set_output_path(output.clone());
+    }
+
+    // Installed before starting, so that the recording is never left
+    // unfinished by being killed.
+    let (signal_tx, signal_rx) = oneshot::channel();
+    let signal_tx = Rc::new(RefCell::new(Some(signal_tx)));
+    let signal_source_ids = [libc::SIGINT, libc::SIGTERM].map(|signum| {
+        let signal_tx = Rc::clone(&signal_tx);
+        unix_signal_add_local(signum, move || {
+            if let Some(tx) = signal_tx.take() {
+                let _ = tx.send(());
+            }
+            glib::ControlFlow::Continue
+        })
+    });
+
+    let (finished_tx, finished_rx) = oneshot::channel();
+    let finished_tx = RefCell::new(Some(finished_tx));
+    recording.connect_finished(move |_, res| {
+        if let Some(tx) = finished_tx.take() {
+            let _ = tx.send(res.as_ref().map(|(files, _)| files.clone()).map_err(|err| {
+                // `anyhow::Error` is not `Clone`, so carry over its full chain.
+                anyhow!("{:?}", err)
+            }));
+        }
+    });
+
+    recording.connect_warning(|_, message| eprintln!("{}", message));
+
+    let state_handler_id = recording.connect_state_notify(|recording| match recording.state() {
+        RecordingState::Delayed { secs_left } => eprintln!("Recording in {}…", secs_left),
+        RecordingState::Recording => eprintln!("Recording…"),
+        RecordingState::Flushing { progress: 0 } => eprintln!("Flushing…"),
+        _ => {}
+    });
+
+    recording.start(None::<&gtk::Window>, &settings).await;
+
+    // Starting may have failed before even reaching the recording state.
+    let mut finished_rx = finished_rx.fuse();
+    let res = if let Some(res) = (&mut finished_rx).now_or_never() {
+        res
+    } else {
+        let stop_request = if let Some(duration) = args.duration {
+            Either::Left(glib::timeout_future(duration))
+        } else {
+            eprintln!("Press Enter to stop recording.");
+            Either::Right(wait_for_stdin_line().boxed_local())
+        };
+        let stop_request = future::select(stop_request, signal_rx);
+
+        match future::select(stop_request, finished_rx).await {
+            Either::Left((_, finished_rx)) => {
+                recording.stop();
+                finished_rx.await
+            }
+            Either::Right((res, _)) => res,
+        }
+    };
+
+    recording.disconnect(state_handler_id);
+    for source_id in signal_source_ids {
+        source_id.remove();
+    }
+
+    // The restore token is the only thing worth keeping across invocations.
+    Settings::default().set_screencast_restore_token(&settings.screencast_restore_token());
+
+    let files = res.context("Recording was dropped")??;
+    ensure!(!files.is_empty(), "Recording produced no files");
+
+    Ok(files)
+}
+
+fn apply_overrides(settings: &Settings, args: &RecordArgs) -> Result<()> {
+    // Selecting an area requires the area selector window.
+    settings.set_capture_mode(CaptureMode::MonitorWindow);
+
+    // A replay buffer has no files of its own to print.
+    settings.set_replay_buffer(false);
+
+    // The limits set in the app would split or stop the recording behind
+    // the caller's back.
+    settings.set_split_duration(0);
+    settings.set_split_size(0);
+    settings.set_stop_duration(0);
+    settings.set_stop_size(0);
+
+    if let Some(ref profile_id) = args.profile_id {
+        let profile = Profile::from_id(profile_id)?;
+        ensure!(
+            profile.is_available(),
+            "Profile `{}` is not available",
+            profile_id
+        );
+        settings.set_profile(Some(&profile));
+    }
+
+    if let Some(framerate) = args.framerate {
+        settings.set_framerate(framerate);
+    }
+
+    if let Some(delay) = args.delay {
+        settings.set_record_delay(Duration::from_secs(delay as u64));
+    }
+
+    if let Some(ref output) = args.output
+        && output.is_dir()
+    {
+        settings.set_saving_location(output);
+    }
+
+    if args.no_audio {
+        settings.set_record_desktop_audio(false);
+        settings.set_record_microphone(false);
+    }
+
+    if args.hide_pointer {
+        settings.set_show_pointer(false);
+    }
+
+    Ok(())
+}
+
+/// Resolves once a line is read from stdin.
+///
+/// Never resolves if stdin is closed or cannot be read, e.g., when run in
+/// the background, so that the recording is then only stopped by the
+/// duration or a signal.
+async fn wait_for_stdin_line() {
+    let (tx, rx) = oneshot::channel();
+
+    thread::spawn(move || {
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) => tracing::debug!("Stdin is closed"),
+            Ok(_) => {
+                let _ = tx.send(());
+            }
+            Err(err) => tracing::warn!("Failed to read stdin: {:?}", err),
+        }
+    });
+
+    if rx.await.is_err() {
+        future::pending::<()>().await;
+    }
+}
+
+/// Calls the function on the default main context whenever the signal is
+/// received, instead of the process being killed by it.
+///
+/// This must be called from the thread that owns the default main context.
+fn unix_signal_add_local<F>(signum: c_int, func: F) -> glib::SourceId
+where
+    F: FnMut() -> glib::ControlFlow + 'static,
+{
+    unsafe extern "C" {
+        fn g_unix_signal_add_full(
+            priority: c_int,
+            signum: c_int,
+            handler: glib::ffi::GSourceFunc,
+            user_data: glib::ffi::gpointer,
+            notify: glib::ffi::GDestroyNotify,
+        ) -> glib::ffi::guint;
+    }
+
+    unsafe extern "C" fn trampoline<F: FnMut() -> glib::ControlFlow + 'static>(
+        func: glib::ffi::gpointer,
+    ) -> glib::ffi::gboolean {
+        let func = unsafe { &mut *(func as *mut F) };
+        func().into_glib()
+    }
+
+    unsafe extern "C" fn destroy<F: FnMut() -> glib::ControlFlow + 'static>(
+        func: glib::ffi::gpointer,
+    ) {
+        drop(unsafe { Box::from_raw(func as *mut F) });
+    }
+
+    assert!(
+        glib::MainContext::default().is_owner(),
+        "Default main context is not owned by this thread"
+    );
+
+    let func = Box::into_raw(Box::new(func));
+    unsafe {
+        from_glib(g_unix_signal_add_full(
+            glib::ffi::G_PRIORITY_DEFAULT,
+            signum,
+            Some(trampoline::<F>),
+            func as glib::ffi::gpointer,
+            Some(destroy::<F>),
+        ))
+    }
+}
+
+#[allow(clippy::print_stdout)]
+fn print_stdout(text: &str) {
+    println!("{}", text);
+}
+
+fn parse_framerate(raw: &str) -> Result<gst::Fraction> {
+    let framerate = if let Some((numer, denom)) = raw.split_once('/') {
+        let numer = numer.trim().parse::<i32>()?;
+        let denom = denom.trim().parse::<i32>()?;
+        ensure!(denom != 0, "Denominator must not be zero");
+        gst::Fraction::new(numer, denom)
+    } else {
+        let float = raw.trim().parse::<f64>()?;
+        gst::Fraction::approximate_f64(float)
+            .with_context(|| format!("Invalid framerate `{}`", raw))?
+    };
+
+    ensure!(
+        framerate > gst::Fraction::from_integer(0),
+        "Framerate must be positive"
+    );
+
+    Ok(framerate)
+}
+
+fn parse_duration(raw: &str) -> Result<Duration> {
+    let raw = raw.trim();
+
+    let (number, multiplier) = match raw.char_indices().last() {
+        Some((index, 's')) => (&raw[..index], 1),
+        Some((index, 'm')) => (&raw[..index], 60),
+        Some((index, 'h')) => (&raw[..index], 60 * 60),
+        _ => (raw, 1),
+    };
+
+    let secs = number
+        .parse::<u64>()
+        .with_context(|| format!("Invalid duration `{}`", raw))?;
+    ensure!(secs > 0, "Duration must be positive");
+
+    Ok(Duration::from_secs(secs * multiplier))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(raw: &[&str]) -> Vec<String> {
+        raw.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_args() {
+        let parsed = RecordArgs::parse(&args(&[
+            "--profile",
+            "mp4",
+            "--framerate",
+            "30",
+            "--duration",
+            "60s",
+            "--output",
+            "/tmp/out.mp4",
+            "--no-audio",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            parsed,
+            RecordArgs {
+                profile_id: Some("mp4".into()),
+                framerate: Some(gst::Fraction::from_integer(30)),
+                duration: Some(Duration::from_secs(60)),
+                output: Some(PathBuf::from("/tmp/out.mp4")),
+                no_audio: true,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_args_invalid() {
+        assert!(RecordArgs::parse(&args(&["--unknown"])).is_err());
+        assert!(RecordArgs::parse(&args(&["--profile"])).is_err());
+        assert!(RecordArgs::parse(&args(&["--delay", "soon"])).is_err());
+    }
+
+    #[test]
+    fn parse_args_action() {
+        assert_eq!(RecordArgs::parse(&[]).unwrap().action, Action::Record);
+        assert_eq!(
+            RecordArgs::parse(&args(&["--no-audio", "--help"]))
+                .unwrap()
+                .action,
+            Action::ShowHelp
+        );
+        assert_eq!(
+            RecordArgs::parse(&args(&["--list-profiles"]))
+                .unwrap()
+                .action,
+            Action::ListProfiles
+        );
+    }
+
+    #[test]
+    fn test_parse_framerate() {
+        assert_eq!(
+            parse_framerate("24").unwrap(),
+            gst::Fraction::from_integer(24)
+        );
+        assert_eq!(
+            parse_framerate("30000/1001").unwrap(),
+            gst::Fraction::new(30_000, 1001)
+        );
+        assert!(parse_framerate("29.97").is_ok());
+        assert!(parse_framerate("0").is_err());
+        assert!(parse_framerate("30/0").is_err());
+        assert!(parse_framerate("fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("10m").unwrap(), Duration::from_secs(600));
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("1d").is_err());
+    }
+}
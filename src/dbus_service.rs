@@ -0,0 +1,274 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use gtk::{
+    gio::{self, prelude::*},
+    glib::{self, clone},
+};
+
+use crate::{
+    application::Application,
+    recording::{Recording, RecordingState},
+};
+
+const INTERFACE_NAME: &str = "io.github.seadve.Kooha.Recorder";
+const INTERFACE_XML: &str = r#"
+<node>
+  <interface name="io.github.seadve.Kooha.Recorder">
+    <method name="StartRecording"/>
+    <method name="StopRecording"/>
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <method name="Cancel"/>
+    <!-- Only available while the replay buffer is recording -->
+    <method name="SaveReplay">
+      <arg name="uri" type="s" direction="out"/>
+    </method>
+    <!-- One of "init", "delayed", "recording", "paused", "flushing", or "finished" -->
+    <property name="State" type="s" access="read"/>
+    <!-- Duration of the current or last recording in nanoseconds -->
+    <property name="Duration" type="t" access="read"/>
+    <!-- Either `uris` or `error` is empty, depending on whether the recording succeeded -->
+    <signal name="Finished">
+      <arg name="uris" type="as"/>
+      <arg name="error" type="s"/>
+    </signal>
+  </interface>
+</node>
+"#;
+
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    StartRecording,
+    StopRecording,
+    Pause,
+    Resume,
+    Cancel,
+    SaveReplay,
+}
+
+impl DBusMethodCall for Method {
+    fn parse_call(
+        _obj_path: &str,
+        _interface: Option<&str>,
+        method: &str,
+        _params: glib::Variant,
+    ) -> Result<Self, glib::Error> {
+        match method {
+            "StartRecording" => Ok(Self::StartRecording),
+            "StopRecording" => Ok(Self::StopRecording),
+            "Pause" => Ok(Self::Pause),
+            "Resume" => Ok(Self::Resume),
+            "Cancel" => Ok(Self::Cancel),
+            "SaveReplay" => Ok(Self::SaveReplay),
+            _ => Err(glib::Error::new(
+                gio::DBusError::UnknownMethod,
+                &format!("Unknown method `{}`", method),
+            )),
+        }
+    }
+}
+
+/// Exports an interface on the application's object path to control
+/// recordings over D-Bus.
+#[derive(Debug, Clone)]
+pub struct DBusService {
+    inner: Rc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    connection: gio::DBusConnection,
+    object_path: String,
+    registration_id: RefCell<Option<gio::RegistrationId>>,
+    recording: RefCell<Option<(Recording, Vec<glib::SignalHandlerId>)>>,
+}
+
+impl Inner {
+    fn state(&self) -> RecordingState {
+        self.recording
+            .borrow()
+            .as_ref()
+            .map_or(RecordingState::Init, |(recording, _)| recording.state())
+    }
+
+    fn duration(&self) -> gst::ClockTime {
+        self.recording
+            .borrow()
+            .as_ref()
+            .map_or(gst::ClockTime::ZERO, |(recording, _)| recording.duration())
+    }
+
+    fn emit_properties_changed(&self, changed: &[(&str, glib::Variant)]) {
+        let changed = changed
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect::<HashMap<_, _>>();
+
+        if let Err(err) = self.connection.emit_signal(
+            None,
+            &self.object_path,
+            "org.freedesktop.DBus.Properties",
+            "PropertiesChanged",
+            Some(&(INTERFACE_NAME, changed, Vec::<String>::new()).to_variant()),
+        ) {
+            tracing::warn!("Failed to emit PropertiesChanged: {:?}", err);
+        }
+    }
+
+    fn emit_finished(&self, uris: &[String], error: &str) {
+        if let Err(err) = self.connection.emit_signal(
+            None,
+            &self.object_path,
+            INTERFACE_NAME,
+            "Finished",
+            Some(&(uris.to_vec(), error).to_variant()),
+        ) {
+            tracing::warn!("Failed to emit Finished: {:?}", err);
+        }
+    }
+}
+
+impl DBusService {
+    pub fn register(
+        connection: &gio::DBusConnection,
+        object_path: &str,
+    ) -> Result<Self, glib::Error> {
+        let node_info = gio::DBusNodeInfo::for_xml(INTERFACE_XML)?;
+        let interface_info = node_info
+            .lookup_interface(INTERFACE_NAME)
+            .expect("interface must be defined in the xml");
+
+        let inner = Rc::new(Inner {
+            connection: connection.clone(),
+            object_path: object_path.to_string(),
+            registration_id: RefCell::new(None),
+            recording: RefCell::new(None),
+        });
+
+        let registration_id = connection
+            .register_object(object_path, &interface_info)
+            .typed_method_call::<Method>()
+            .invoke_and_return_future_local(|_, sender, method| {
+                tracing::debug!(?method, ?sender, "Received D-Bus method call");
+
+                async move {
+                    handle_method_call(method).await.map_err(|err| {
+                        glib::Error::new(gio::DBusError::Failed, &format!("{:#}", err))
+                    })
+                }
+            })
+            .property(clone!(
+                #[weak]
+                inner,
+                #[upgrade_or_else]
+                || ().to_variant(),
+                move |_, _, _, _, property_name| match property_name {
+                    "State" => state_to_str(inner.state()).to_variant(),
+                    "Duration" => inner.duration().nseconds().to_variant(),
+                    _ => unreachable!("unknown property `{}`", property_name),
+                }
+            ))
+            .build()?;
+        inner.registration_id.replace(Some(registration_id));
+
+        Ok(Self { inner })
+    }
+
+    pub fn unregister(&self) {
+        self.unwatch_recording();
+
+        if let Some(registration_id) = self.inner.registration_id.take()
+            && let Err(err) = self.inner.connection.unregister_object(registration_id)
+        {
+            tracing::warn!("Failed to unregister D-Bus object: {:?}", err);
+        }
+    }
+
+    /// Mirrors the state of the given recording on the exported properties
+    /// until another recording is watched.
+    pub fn watch_recording(&self, recording: &Recording) {
+        self.unwatch_recording();
+
+        let handler_ids = vec![
+            recording.connect_state_notify(clone!(
+                #[weak(rename_to = inner)]
+                self.inner,
+                move |recording| {
+                    inner.emit_properties_changed(&[(
+                        "State",
+                        state_to_str(recording.state()).to_variant(),
+                    )]);
+                }
+            )),
+            recording.connect_duration_notify(clone!(
+                #[weak(rename_to = inner)]
+                self.inner,
+                move |recording| {
+                    inner.emit_properties_changed(&[(
+                        "Duration",
+                        recording.duration().nseconds().to_variant(),
+                    )]);
+                }
+            )),
+            recording.connect_finished(clone!(
+                #[weak(rename_to = inner)]
+                self.inner,
+                move |_, res| match res {
+                    Ok((files, _)) => {
+                        let uris = files
+                            .iter()
+                            .map(|file| file.uri().into())
+                            .collect::<Vec<_>>();
+                        inner.emit_finished(&uris, "");
+                    }
+                    Err(err) => inner.emit_finished(&[], &format!("{:#}", err)),
+                }
+            )),
+        ];
+        self.inner
+            .recording
+            .replace(Some((recording.clone(), handler_ids)));
+
+        self.inner.emit_properties_changed(&[
+            ("State", state_to_str(recording.state()).to_variant()),
+            ("Duration", recording.duration().nseconds().to_variant()),
+        ]);
+    }
+
+    fn unwatch_recording(&self) {
+        if let Some((recording, handler_ids)) = self.inner.recording.take() {
+            for handler_id in handler_ids {
+                recording.disconnect(handler_id);
+            }
+        }
+    }
+}
+
+async fn handle_method_call(method: Method) -> anyhow::Result<Option<glib::Variant>> {
+    let window = Application::get().window();
+
+    match method {
+        Method::StartRecording => window.start_record()?,
+        Method::StopRecording => window.stop_record()?,
+        Method::Pause => window.pause_record()?,
+        Method::Resume => window.resume_record()?,
+        Method::Cancel => window.cancel_record()?,
+        Method::SaveReplay => {
+            let file = window.save_replay().await?;
+            return Ok(Some((file.uri().as_str(),).to_variant()));
+        }
+    }
+
+    Ok(None)
+}
+
+fn state_to_str(state: RecordingState) -> &'static str {
+    match state {
+        RecordingState::Init => "init",
+        RecordingState::Delayed { .. } => "delayed",
+        RecordingState::Recording => "recording",
+        RecordingState::Paused => "paused",
+        RecordingState::Flushing { .. } => "flushing",
+        RecordingState::Finished => "finished",
+    }
+}
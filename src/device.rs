@@ -1,8 +1,10 @@
+use std::sync::OnceLock;
+
 use anyhow::{Context, Result, anyhow, ensure};
 use gettextrs::gettext;
 use gst::prelude::*;
 
-use crate::help::ContextWithHelp;
+use crate::{help::ContextWithHelp, i18n::gettext_f};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DeviceClass {
@@ -19,23 +21,98 @@ impl DeviceClass {
     }
 }
 
-pub fn find_default(class: DeviceClass) -> Result<gst::Device> {
-    let provider =
-        gst::DeviceProviderFactory::by_name("pulsedeviceprovider").with_context(|| {
-            ContextWithHelp::new(
-                gettext("Failed to find the default audio device"),
-                gettext("Make sure that you have PulseAudio installed in your system."),
-            )
-        })?;
+/// The sound server that audio is captured from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    PipeWire,
+    PulseAudio,
+}
 
-    provider.start()?;
-    let devices = provider.devices();
-    provider.stop();
+impl Backend {
+    /// Returns `PipeWire` if it is running and its plugin is installed,
+    /// otherwise `PulseAudio`.
+    pub fn get() -> Self {
+        static BACKEND: OnceLock<Backend> = OnceLock::new();
+
+        *BACKEND.get_or_init(|| {
+            let backend = match Self::PipeWire.start_provider() {
+                Ok(provider) => {
+                    provider.stop();
+                    Self::PipeWire
+                }
+                Err(err) => {
+                    tracing::debug!("PipeWire audio is not available: {:?}", err);
+                    Self::PulseAudio
+                }
+            };
 
-    tracing::debug!("Finding device name for class `{:?}`", class);
+            tracing::debug!("Using {:?} audio backend", backend);
+
+            backend
+        })
+    }
 
-    for device in devices {
-        if let Err(err) = validate_device(&device, class) {
+    fn device_provider_name(self) -> &'static str {
+        match self {
+            Self::PipeWire => "pipewiredeviceprovider",
+            Self::PulseAudio => "pulsedeviceprovider",
+        }
+    }
+
+    fn start_provider(self) -> Result<gst::DeviceProvider> {
+        let provider = gst::DeviceProviderFactory::by_name(self.device_provider_name())
+            .with_context(|| format!("Failed to create `{}`", self.device_provider_name()))?;
+        provider.start()?;
+
+        Ok(provider)
+    }
+}
+
+/// Creates a new audio src element with the given name, recording the
+/// given device or the default one of the class if there is none.
+///
+/// If the class is a sink, the audio played on it is recorded instead.
+pub fn make_src(
+    class: DeviceClass,
+    device: Option<&gst::Device>,
+    element_name: &str,
+) -> Result<gst::Element> {
+    match Backend::get() {
+        Backend::PipeWire => make_pipewiresrc(class, device, element_name),
+        Backend::PulseAudio => make_pulsesrc(class, device, element_name),
+    }
+}
+
+/// Returns the identifier of the device, which stays the same across
+/// reconnects and restarts, unlike its display name.
+///
+/// On `PipeWire`, this is the node name, which matches the device name
+/// exposed by its pulse server, so saved devices carry over between the
+/// backends.
+pub fn id(device: &gst::Device) -> Result<String> {
+    let device_name = match Backend::get() {
+        Backend::PipeWire => device
+            .properties()
+            .context("No properties")?
+            .get::<String>("node.name")
+            .context("No `node.name` property")?,
+        Backend::PulseAudio => {
+            let element = device.create_element(None)?;
+            element
+                .property::<Option<String>>("device")
+                .context("No device name")?
+        }
+    };
+    ensure!(!device_name.is_empty(), "Empty device name");
+
+    Ok(device_name)
+}
+
+fn find_default(class: DeviceClass) -> Result<gst::Device> {
+    tracing::debug!("Finding default device for class `{:?}`", class);
+
+    for device in all(class)? {
+        if let Err(err) = validate_default_device(&device) {
             tracing::debug!("Skipping device `{}`: {:?}", device.name(), err);
             continue;
         }
@@ -46,13 +123,49 @@ pub fn find_default(class: DeviceClass) -> Result<gst::Device> {
     Err(anyhow!("Failed to find a default device"))
 }
 
-fn validate_device(device: &gst::Device, class: DeviceClass) -> Result<()> {
-    ensure!(
-        device.has_classes(class.as_str()),
-        "Unknown device class `{}`",
-        device.device_class()
-    );
+/// Finds the device with the given id, as returned by `id`.
+pub fn find_by_id(class: DeviceClass, id: &str) -> Result<gst::Device> {
+    tracing::debug!("Finding device with id `{}` for class `{:?}`", id, class);
 
+    for device in all(class)? {
+        match self::id(&device) {
+            Ok(device_id) if device_id == id => return Ok(device),
+            Ok(_) => {}
+            Err(err) => tracing::debug!("Skipping device `{}`: {:?}", device.name(), err),
+        }
+    }
+
+    Err(anyhow!("Failed to find device with id `{}`", id)).with_context(|| {
+        ContextWithHelp::new(
+            gettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "The audio device “{id}” is not available",
+                &[("id", id)],
+            ),
+            gettext("Connect the device or select another one in the preferences."),
+        )
+    })
+}
+
+/// Returns all the devices of the given class.
+pub fn all(class: DeviceClass) -> Result<Vec<gst::Device>> {
+    let provider = Backend::get().start_provider().with_context(|| {
+        ContextWithHelp::new(
+            gettext("Failed to find the audio devices"),
+            gettext("Make sure that you have PipeWire or PulseAudio running in your system."),
+        )
+    })?;
+
+    let devices = provider.devices();
+    provider.stop();
+
+    Ok(devices
+        .into_iter()
+        .filter(|device| device.has_classes(class.as_str()))
+        .collect())
+}
+
+fn validate_default_device(device: &gst::Device) -> Result<()> {
     let is_default = device
         .properties()
         .context("No properties")?
@@ -63,3 +176,81 @@ fn validate_device(device: &gst::Device, class: DeviceClass) -> Result<()> {
 
     Ok(())
 }
+
+fn make_pipewiresrc(
+    class: DeviceClass,
+    device: Option<&gst::Device>,
+    element_name: &str,
+) -> Result<gst::Element> {
+    let pipewiresrc = gst::ElementFactory::make("pipewiresrc")
+        .name(element_name)
+        .property("do-timestamp", true)
+        .property("provide-clock", false)
+        .build()?;
+
+    // Without a target, the session manager links the stream to the default
+    // device, and keeps following it when it changes.
+    let target = device.map(id).transpose()?;
+    if let Some(ref target) = target {
+        pipewiresrc.set_property("target-object", target);
+    }
+
+    if class == DeviceClass::Sink {
+        pipewiresrc.set_property(
+            "stream-properties",
+            gst::Structure::builder("props")
+                .field("stream.capture.sink", "true")
+                .build(),
+        );
+    }
+
+    tracing::debug!(
+        "Found {:?} with name `{}`",
+        class,
+        target.as_deref().unwrap_or("<default>")
+    );
+
+    Ok(pipewiresrc)
+}
+
+/// If the class is already a source, it will record the device as is,
+/// otherwise, if it is a sink, it will record the `.monitor` of the device.
+fn make_pulsesrc(
+    class: DeviceClass,
+    device: Option<&gst::Device>,
+    element_name: &str,
+) -> Result<gst::Element> {
+    let device = match device {
+        Some(device) => device.clone(),
+        None => find_default(class)?,
+    };
+
+    let pulsesrc = gst::ElementFactory::make("pulsesrc")
+        .name(element_name)
+        .property("provide-clock", false)
+        .property("do-timestamp", true)
+        .build()?;
+
+    match class {
+        DeviceClass::Sink => {
+            let device_name = id(&device)?;
+
+            let monitor_name = format!("{}.monitor", device_name);
+            pulsesrc.set_property("device", &monitor_name);
+
+            tracing::debug!("Found desktop audio with name `{}`", monitor_name);
+        }
+        DeviceClass::Source => {
+            device.reconfigure_element(&pulsesrc)?;
+
+            let device_name = pulsesrc
+                .property::<Option<String>>("device")
+                .context("No device name")?;
+            ensure!(!device_name.is_empty(), "Empty device name");
+
+            tracing::debug!("Found microphone with name `{}`", device_name);
+        }
+    }
+
+    Ok(pulsesrc)
+}
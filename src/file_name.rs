@@ -0,0 +1,202 @@
+use anyhow::{Result, bail, ensure};
+use gtk::glib;
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+
+use crate::{format, settings::CaptureMode};
+
+/// Template that results in the same names as before it was configurable.
+pub const DEFAULT_TEMPLATE: &str = "Kooha-{year}-{month}-{day}-{hour}-{minute}-{second}";
+
+/// Values of the tokens of a file name template, other than the date, time,
+/// and counter.
+#[derive(Debug, Clone)]
+pub struct FileNameValues {
+    pub profile_id: String,
+    pub capture_mode: CaptureMode,
+    /// Expected size of the video, if known
+    pub size: Option<(i32, i32)>,
+    pub framerate: gst::Fraction,
+}
+
+/// Returns whether the template takes a number from the counter, which must
+/// only be advanced then.
+pub fn uses_counter(template: &str) -> bool {
+    template.contains("{counter}")
+}
+
+/// Replaces the tokens of the template, failing if it has an unknown token
+/// or if the result is not a valid file name.
+pub fn render(
+    template: &str,
+    values: &FileNameValues,
+    now: &glib::DateTime,
+    counter: u32,
+) -> Result<String> {
+    let size_text = |f: fn((i32, i32)) -> String| values.size.map_or("unknown".into(), f);
+
+    let mut file_name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        let (literal, token) = rest.split_at(start);
+        file_name.push_str(literal);
+
+        ensure!(token.starts_with('{'), "Unmatched `}}` in template");
+        let Some(end) = token.find('}') else {
+            bail!("Unclosed `{{` in template");
+        };
+
+        let value = match &token[1..end] {
+            "year" => format!("{:04}", now.year()),
+            "month" => format!("{:02}", now.month()),
+            "day" => format!("{:02}", now.day_of_month()),
+            "hour" => format!("{:02}", now.hour()),
+            "minute" => format!("{:02}", now.minute()),
+            "second" => format!("{:02}", now.second()),
+            "profile" => values.profile_id.clone(),
+            "mode" => match values.capture_mode {
+                CaptureMode::MonitorWindow => "normal".into(),
+                CaptureMode::Selection => "selection".into(),
+            },
+            "width" => size_text(|(width, _)| width.to_string()),
+            "height" => size_text(|(_, height)| height.to_string()),
+            "resolution" => size_text(|(width, height)| format!("{}x{}", width, height)),
+            "framerate" => format::framerate(values.framerate),
+            "counter" => counter.to_string(),
+            other => bail!("Unknown token `{{{}}}` in template", other),
+        };
+        file_name.push_str(&value);
+
+        rest = &token[end + 1..];
+    }
+    file_name.push_str(rest);
+
+    let file_name = file_name.trim();
+    ensure!(!file_name.is_empty(), "Template results in an empty name");
+    ensure!(
+        !file_name.contains(['/', '\0']) && file_name != "." && file_name != "..",
+        "Template results in an invalid name `{}`",
+        file_name
+    );
+
+    Ok(file_name.to_string())
+}
+
+/// Returns the path of the file name with the extension in the directory,
+/// which gets a numeric suffix if a file with that name already exists.
+pub fn unique_path(dir: &Path, file_name: &str, extension: &OsStr) -> PathBuf {
+    let path_with_suffix = |suffix: Option<u32>| {
+        let file_name = match suffix {
+            Some(suffix) => format!("{}-{}", file_name, suffix),
+            None => file_name.to_string(),
+        };
+        // Pushed instead of set, as the name may contain a dot.
+        let mut file_name = PathBuf::from(file_name).into_os_string();
+        file_name.push(".");
+        file_name.push(extension);
+        dir.join(file_name)
+    };
+
+    let path = path_with_suffix(None);
+    if !path.exists() {
+        return path;
+    }
+
+    (2..)
+        .map(|suffix| path_with_suffix(Some(suffix)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    fn values() -> FileNameValues {
+        FileNameValues {
+            profile_id: "webm".into(),
+            capture_mode: CaptureMode::Selection,
+            size: Some((1280, 720)),
+            framerate: gst::Fraction::new(30_000, 1001),
+        }
+    }
+
+    fn now() -> glib::DateTime {
+        glib::DateTime::new(&glib::TimeZone::utc(), 2024, 1, 2, 3, 4, 5.5).unwrap()
+    }
+
+    #[test]
+    fn render_default() {
+        assert_eq!(
+            render(DEFAULT_TEMPLATE, &values(), &now(), 1).unwrap(),
+            now().format("Kooha-%F-%H-%M-%S").unwrap()
+        );
+    }
+
+    #[test]
+    fn render_tokens() {
+        assert_eq!(
+            render(
+                "{profile}_{mode}_{resolution}_{width}_{height}@{framerate}_{counter}",
+                &values(),
+                &now(),
+                42
+            )
+            .unwrap(),
+            "webm_selection_1280x720_1280_720@29.97_42"
+        );
+        assert_eq!(
+            render(
+                "{resolution}",
+                &FileNameValues {
+                    size: None,
+                    ..values()
+                },
+                &now(),
+                1
+            )
+            .unwrap(),
+            "unknown"
+        );
+        assert_eq!(render("plain", &values(), &now(), 1).unwrap(), "plain");
+    }
+
+    #[test]
+    fn render_invalid() {
+        assert!(render("{unknown}", &values(), &now(), 1).is_err());
+        assert!(render("{year", &values(), &now(), 1).is_err());
+        assert!(render("year}", &values(), &now(), 1).is_err());
+        assert!(render("a/{year}", &values(), &now(), 1).is_err());
+        assert!(render("  ", &values(), &now(), 1).is_err());
+        assert!(render("..", &values(), &now(), 1).is_err());
+    }
+
+    #[test]
+    fn counter() {
+        assert!(uses_counter("Kooha-{counter}"));
+        assert!(!uses_counter(DEFAULT_TEMPLATE));
+    }
+
+    #[test]
+    fn unique_path_suffix() {
+        let dir = glib::mkdtemp("kooha-file-name-XXXXXX").unwrap();
+        let extension = OsStr::new("mp4");
+
+        let path = unique_path(&dir, "v1.2", extension);
+        assert_eq!(path, dir.join("v1.2.mp4"));
+
+        fs::write(&path, "").unwrap();
+        let path = unique_path(&dir, "v1.2", extension);
+        assert_eq!(path, dir.join("v1.2-2.mp4"));
+
+        fs::write(&path, "").unwrap();
+        assert_eq!(unique_path(&dir, "v1.2", extension), dir.join("v1.2-3.mp4"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
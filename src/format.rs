@@ -1,3 +1,5 @@
+use gtk::glib;
+
 use crate::i18n::ngettext_f;
 
 /// Formats a framerate in a more human-readable format.
@@ -23,6 +25,30 @@ pub fn digital_clock(clock_time: gst::ClockTime) -> String {
     format!("{:02}∶{:02}", minutes_display, seconds_display)
 }
 
+/// Formats time in HH:MM:SS, which is meant to be burned into the video.
+///
+/// The HH part will be more than 2 digits if the time is >= 100 hours.
+pub fn elapsed_time(clock_time: gst::ClockTime) -> String {
+    let secs = clock_time.seconds();
+
+    let hours_display = secs / 3600;
+    let minutes_display = (secs % 3600) / 60;
+    let seconds_display = secs % 60;
+    format!(
+        "{:02}:{:02}:{:02}",
+        hours_display, minutes_display, seconds_display
+    )
+}
+
+/// Formats a wall-clock time with its date in the locale's format, e.g., for
+/// when a recording is scheduled.
+pub fn date_time(date_time: &glib::DateTime) -> String {
+    date_time
+        .format("%x %R")
+        .expect("format is valid")
+        .to_string()
+}
+
 /// Formats time as duration.
 pub fn duration(clock_time: gst::ClockTime) -> String {
     let secs = clock_time.seconds();
@@ -97,6 +123,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_elapsed_time() {
+        assert_eq!(elapsed_time(gst::ClockTime::ZERO), "00:00:00");
+        assert_eq!(
+            elapsed_time(gst::ClockTime::from_mseconds(59_999)),
+            "00:00:59"
+        );
+        assert_eq!(
+            elapsed_time(gst::ClockTime::from_seconds(2 * 60 * 60 + 3 * 60 + 4)),
+            "02:03:04"
+        );
+    }
+
     #[test]
     fn digital_clock_less_than_1_hour() {
         assert_eq!(digital_clock(gst::ClockTime::ZERO), "00∶00");
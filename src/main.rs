@@ -27,22 +27,34 @@ mod about;
 mod application;
 mod area_selector;
 mod cancelled;
+mod cli;
 mod config;
+mod dbus_service;
 mod device;
 mod experimental;
+mod file_name;
 mod format;
 mod help;
 mod i18n;
 mod item_row;
+mod pause_gate;
 mod pipeline;
+mod post_recording_hook;
 mod preferences_dialog;
 mod profile;
+mod profile_editor;
 mod recording;
+mod recovery;
+mod replay_buffer;
+mod schedule;
 mod screencast_portal;
 mod settings;
 mod timer;
+mod webcam;
 mod window;
 
+use std::env;
+
 use gettextrs::{LocaleCategory, gettext};
 use gtk::{gio, glib};
 
@@ -67,6 +79,11 @@ fn main() -> glib::ExitCode {
     let res = gio::Resource::load(RESOURCES_FILE).expect("Could not load gresource file.");
     gio::resources_register(&res);
 
+    let args = env::args().collect::<Vec<_>>();
+    if args.get(1).is_some_and(|arg| arg == "record") {
+        return cli::run(&args[2..]);
+    }
+
     let app = Application::new();
     app.run()
 }
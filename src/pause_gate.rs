@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex};
+
+use gst::prelude::*;
+
+/// Drops the buffers of a recording while it is paused, and moves the later
+/// ones back by the time spent paused, so that the output continues where it
+/// was paused instead of having a gap or a frozen frame.
+///
+/// The pauses are in running time, which is shared by all the streams of the
+/// pipeline, so they are cut at the same points and stay in sync.
+#[derive(Debug, Default, Clone)]
+pub struct PauseGate {
+    pauses: Arc<Mutex<Pauses>>,
+}
+
+impl PauseGate {
+    /// Drops and moves back the buffers that pass through the pad.
+    pub fn attach(&self, pad: &gst::Pad) {
+        let pauses = Arc::clone(&self.pauses);
+        pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            let Some(running_time) = info
+                .buffer()
+                .and_then(|buffer| buffer_running_time(pad, buffer))
+            else {
+                return gst::PadProbeReturn::Ok;
+            };
+
+            let Some(offset) = pauses.lock().unwrap().offset(running_time) else {
+                return gst::PadProbeReturn::Drop;
+            };
+
+            if !offset.is_zero()
+                && let Some(buffer) = info.buffer_mut()
+            {
+                let buffer = buffer.make_mut();
+                buffer.set_pts(buffer.pts().map(|pts| pts.saturating_sub(offset)));
+                buffer.set_dts(buffer.dts().map(|dts| dts.saturating_sub(offset)));
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    /// Starts dropping the buffers from the running time on.
+    pub fn pause(&self, running_time: gst::ClockTime) {
+        let mut pauses = self.pauses.lock().unwrap();
+
+        debug_assert!(pauses.current.is_none(), "already paused");
+
+        pauses.current.get_or_insert(running_time);
+    }
+
+    /// Stops dropping the buffers from the running time on.
+    pub fn resume(&self, running_time: gst::ClockTime) {
+        let mut pauses = self.pauses.lock().unwrap();
+
+        debug_assert!(pauses.current.is_some(), "not paused");
+
+        if let Some(start) = pauses.current.take() {
+            pauses.finished.push((start, running_time.max(start)));
+        }
+    }
+
+    /// Returns where the running time ends up in the output, which leaves
+    /// out the time spent paused.
+    pub fn output_time(&self, running_time: gst::ClockTime) -> gst::ClockTime {
+        self.pauses.lock().unwrap().output_time(running_time)
+    }
+}
+
+/// Returns the running time of the buffer from the segment on the pad.
+pub fn buffer_running_time(pad: &gst::Pad, buffer: &gst::BufferRef) -> Option<gst::ClockTime> {
+    let pts = buffer.pts()?;
+    let segment_event = pad.sticky_event::<gst::event::Segment>(0)?;
+    let segment = segment_event.segment().downcast_ref::<gst::ClockTime>()?;
+    segment.to_running_time(pts)
+}
+
+#[derive(Debug, Default)]
+struct Pauses {
+    /// Start and end running times of the pauses that were resumed, in order
+    finished: Vec<(gst::ClockTime, gst::ClockTime)>,
+    /// Start running time of the ongoing pause
+    current: Option<gst::ClockTime>,
+}
+
+impl Pauses {
+    fn intervals(&self) -> impl Iterator<Item = (gst::ClockTime, gst::ClockTime)> + '_ {
+        self.finished
+            .iter()
+            .copied()
+            .chain(self.current.map(|start| (start, gst::ClockTime::MAX)))
+    }
+
+    fn output_time(&self, running_time: gst::ClockTime) -> gst::ClockTime {
+        let paused_duration = self
+            .intervals()
+            .map(|(start, end)| running_time.clamp(start, end) - start)
+            .sum::<gst::ClockTime>();
+        running_time - paused_duration
+    }
+
+    /// Returns how much the buffer at the running time has to be moved back,
+    /// or `None` if it is within a pause and has to be dropped.
+    fn offset(&self, running_time: gst::ClockTime) -> Option<gst::ClockTime> {
+        if self
+            .intervals()
+            .any(|(start, end)| (start..end).contains(&running_time))
+        {
+            return None;
+        }
+
+        Some(running_time - self.output_time(running_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> gst::ClockTime {
+        gst::ClockTime::from_mseconds(ms)
+    }
+
+    #[test]
+    fn pauses() {
+        let gate = PauseGate::default();
+        assert_eq!(gate.output_time(ms(100)), ms(100));
+
+        gate.pause(ms(100));
+        assert_eq!(gate.output_time(ms(150)), ms(100));
+
+        gate.resume(ms(300));
+        assert_eq!(gate.output_time(ms(300)), ms(100));
+        assert_eq!(gate.output_time(ms(400)), ms(200));
+
+        let pauses = gate.pauses.lock().unwrap();
+        assert_eq!(pauses.offset(ms(99)), Some(ms(0)));
+        assert_eq!(pauses.offset(ms(100)), None);
+        assert_eq!(pauses.offset(ms(299)), None);
+        assert_eq!(pauses.offset(ms(300)), Some(ms(200)));
+        assert_eq!(pauses.offset(ms(400)), Some(ms(200)));
+    }
+
+    #[test]
+    fn pauses_keep_streams_in_sync() {
+        /// Returns the output times of the buffers that are kept.
+        fn pass(pauses: &Pauses, buffer_duration: gst::ClockTime) -> Vec<gst::ClockTime> {
+            (0..)
+                .map(|i| buffer_duration * i)
+                .take_while(|running_time| *running_time < ms(10_000))
+                .filter_map(|running_time| {
+                    pauses
+                        .offset(running_time)
+                        .map(|offset| running_time - offset)
+                })
+                .collect()
+        }
+
+        let gate = PauseGate::default();
+        for i in 0..40 {
+            gate.pause(ms(i * 250 + 7));
+            gate.resume(ms(i * 250 + 7 + 113));
+        }
+        let pauses = gate.pauses.lock().unwrap();
+
+        let video = pass(&pauses, gst::ClockTime::from_nseconds(33_333_333));
+        let audio = pass(&pauses, ms(10));
+
+        for times in [&video, &audio] {
+            assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+            // The time spent paused leaves no gap larger than a buffer.
+            assert!(times.windows(2).all(|pair| pair[1] - pair[0] < ms(34)));
+        }
+
+        let expected_end = pauses.output_time(ms(10_000));
+        assert!(expected_end - *video.last().unwrap() < ms(34));
+        assert!(expected_end - *audio.last().unwrap() < ms(10));
+    }
+}
@@ -1,17 +1,111 @@
-use anyhow::{Context, Ok, Result, bail, ensure};
+use anyhow::{Context, Ok, Result, bail};
 use gst::prelude::*;
-use gtk::graphene::Rect;
+use gtk::{glib, graphene::Rect};
 
-use std::{os::unix::io::RawFd, path::PathBuf};
+use std::{
+    iter,
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+};
 
 use crate::{
     area_selector::SelectAreaData,
     device::{self, DeviceClass},
-    profile::Profile,
+    pause_gate::PauseGate,
+    profile::{Profile, Quality},
+    replay_buffer::ReplayBuffer,
     screencast_portal::Stream,
+    settings::{TimestampPosition, WatermarkPosition},
+    webcam::WebcamOverlay,
 };
 
 const AUDIO_SAMPLE_RATE: i32 = 48_000;
+const LEVEL_INTERVAL: gst::ClockTime = gst::ClockTime::from_mseconds(100);
+
+/// Gap between the watermark and the edges of the video, in pixels.
+const WATERMARK_MARGIN: i32 = 24;
+
+/// Name of the `textoverlay` element, to change the text while recording.
+pub const TEXT_OVERLAY_NAME: &str = "kooha-textoverlay";
+
+/// Name of the `textoverlay` element showing the timestamp, which is kept up to
+/// date by the recording.
+pub const TIMESTAMP_OVERLAY_NAME: &str = "kooha-timestamp-overlay";
+
+/// Name of the `level` element on the desktop audio branch.
+pub const DESKTOP_AUDIO_LEVEL_NAME: &str = "kooha-desktop-audio-level";
+/// Name of the `level` element on the microphone branch.
+pub const MICROPHONE_LEVEL_NAME: &str = "kooha-microphone-level";
+
+/// Duration and size at which a recording, or one of its files, ends, where
+/// `None` means no limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_duration: Option<gst::ClockTime>,
+    /// In bytes
+    pub max_size: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_enabled(&self) -> bool {
+        self.max_duration.is_some() || self.max_size.is_some()
+    }
+
+    /// Returns whether any of the limits is reached.
+    pub fn is_reached(&self, duration: gst::ClockTime, size: u64) -> bool {
+        self.max_duration
+            .is_some_and(|max_duration| duration >= max_duration)
+            || self.max_size.is_some_and(|max_size| size >= max_size)
+    }
+}
+
+/// Limits when splitting a recording into consecutive files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SplitLimits {
+    /// Limits of each file
+    pub per_file: Limits,
+    /// Number of the most recent files to keep, older ones are deleted.
+    pub max_files: Option<u32>,
+}
+
+impl SplitLimits {
+    pub fn is_enabled(&self) -> bool {
+        self.per_file.is_enabled()
+    }
+}
+
+/// An image burned in a corner of the recording.
+#[derive(Debug, Clone)]
+pub struct Watermark {
+    pub path: PathBuf,
+    pub position: WatermarkPosition,
+    /// In range 0..=1
+    pub opacity: f64,
+}
+
+/// An audio src with what to do with it before mixing.
+#[derive(Debug)]
+struct AudioSource {
+    element: gst::Element,
+    filters: AudioFilters,
+    level_name: &'static str,
+}
+
+/// The processing applied to an audio src before mixing.
+#[derive(Debug, Clone, Copy)]
+struct AudioFilters {
+    volume: f64,
+    cleanup: bool,
+}
+
+impl Default for AudioFilters {
+    fn default() -> Self {
+        Self {
+            volume: 1.0,
+            cleanup: false,
+        }
+    }
+}
 
 #[derive(Debug)]
 #[must_use]
@@ -23,29 +117,63 @@ pub struct PipelineBuilder {
     streams: Vec<Stream>,
     record_desktop_audio: bool,
     record_microphone: bool,
+    desktop_audio_device: Option<gst::Device>,
+    microphone_device: Option<gst::Device>,
+    desktop_audio_filters: AudioFilters,
+    microphone_filters: AudioFilters,
+    separate_audio_tracks: bool,
     select_area_data: Option<SelectAreaData>,
+    webcam_overlay: Option<WebcamOverlay>,
+    watermark: Option<Watermark>,
+    text_overlay: Option<String>,
+    timestamp_position: Option<TimestampPosition>,
+    max_output_height: Option<u32>,
+    quality: Quality,
+    split_limits: SplitLimits,
+    replay_buffer: Option<ReplayBuffer>,
+    pause_gate: Option<PauseGate>,
 }
 
 impl PipelineBuilder {
     pub fn new(
-        file_path: PathBuf,
         framerate: gst::Fraction,
         profile: Profile,
         fd: RawFd,
         streams: Vec<Stream>,
     ) -> Self {
         Self {
-            file_path,
+            file_path: PathBuf::new(),
             framerate,
             profile,
             fd,
             streams,
             record_desktop_audio: false,
             record_microphone: false,
+            desktop_audio_device: None,
+            microphone_device: None,
+            desktop_audio_filters: AudioFilters::default(),
+            microphone_filters: AudioFilters::default(),
+            separate_audio_tracks: false,
             select_area_data: None,
+            webcam_overlay: None,
+            watermark: None,
+            text_overlay: None,
+            timestamp_position: None,
+            max_output_height: None,
+            quality: Quality::default(),
+            split_limits: SplitLimits::default(),
+            replay_buffer: None,
+            pause_gate: None,
         }
     }
 
+    /// Sets where the recording is written to, which must be set unless
+    /// there is a replay buffer.
+    pub fn file_path(&mut self, file_path: PathBuf) -> &mut Self {
+        self.file_path = file_path;
+        self
+    }
+
     pub fn record_desktop_audio(&mut self, record_desktop_audio: bool) -> &mut Self {
         self.record_desktop_audio = record_desktop_audio;
         self
@@ -56,19 +184,157 @@ impl PipelineBuilder {
         self
     }
 
+    /// Records the output of the given device instead of the default one.
+    pub fn desktop_audio_device(&mut self, device: gst::Device) -> &mut Self {
+        self.desktop_audio_device = Some(device);
+        self
+    }
+
+    /// Records the given device instead of the default microphone.
+    pub fn microphone_device(&mut self, device: gst::Device) -> &mut Self {
+        self.microphone_device = Some(device);
+        self
+    }
+
+    /// Sets the linear gain applied to the desktop audio before mixing.
+    pub fn desktop_audio_volume(&mut self, volume: f64) -> &mut Self {
+        self.desktop_audio_filters.volume = volume;
+        self
+    }
+
+    /// Sets the linear gain applied to the microphone before mixing.
+    pub fn microphone_volume(&mut self, volume: f64) -> &mut Self {
+        self.microphone_filters.volume = volume;
+        self
+    }
+
+    /// Filters out rumble and background noise from the microphone, and
+    /// mutes it when nobody is speaking.
+    pub fn microphone_cleanup(&mut self, microphone_cleanup: bool) -> &mut Self {
+        self.microphone_filters.cleanup = microphone_cleanup;
+        self
+    }
+
+    /// Records desktop audio and microphone as separate tracks instead of
+    /// mixing them, if the profile supports multiple audio tracks.
+    pub fn separate_audio_tracks(&mut self, separate_audio_tracks: bool) -> &mut Self {
+        self.separate_audio_tracks = separate_audio_tracks;
+        self
+    }
+
     pub fn select_area_data(&mut self, data: SelectAreaData) -> &mut Self {
         self.select_area_data = Some(data);
         self
     }
 
+    /// Scales the video down, with the aspect ratio preserved, to fit in
+    /// a 16:9 box, either landscape or portrait, of the given height.
+    pub fn max_output_height(&mut self, max_output_height: u32) -> &mut Self {
+        self.max_output_height = Some(max_output_height);
+        self
+    }
+
+    pub fn quality(&mut self, quality: Quality) -> &mut Self {
+        self.quality = quality;
+        self
+    }
+
+    /// Draws the webcam on top of the screen, after it is cropped.
+    pub fn webcam_overlay(&mut self, webcam_overlay: WebcamOverlay) -> &mut Self {
+        self.webcam_overlay = Some(webcam_overlay);
+        self
+    }
+
+    /// Burns in the image on top of everything else.
+    pub fn watermark(&mut self, watermark: Watermark) -> &mut Self {
+        self.watermark = Some(watermark);
+        self
+    }
+
+    /// Burns in a line of text at the bottom, which can be changed while
+    /// recording through the element named `TEXT_OVERLAY_NAME`.
+    pub fn text_overlay(&mut self, text: &str) -> &mut Self {
+        self.text_overlay = Some(text.to_string());
+        self
+    }
+
+    /// Burns in a timestamp in the given corner, whose text must be set
+    /// through the element named `TIMESTAMP_OVERLAY_NAME`.
+    pub fn timestamp_overlay(&mut self, position: TimestampPosition) -> &mut Self {
+        self.timestamp_position = Some(position);
+        self
+    }
+
+    /// Splits the output into consecutive files named after the file path
+    /// with a sequence suffix, if any of the limits is set.
+    pub fn split_limits(&mut self, split_limits: SplitLimits) -> &mut Self {
+        self.split_limits = split_limits;
+        self
+    }
+
+    /// Keeps the encoded streams in the replay buffer instead of writing
+    /// them to the file path.
+    pub fn replay_buffer(&mut self, replay_buffer: ReplayBuffer) -> &mut Self {
+        self.replay_buffer = Some(replay_buffer);
+        self
+    }
+
+    /// Drops and moves back the raw streams with the gate, before they are
+    /// encoded.
+    pub fn pause_gate(&mut self, pause_gate: PauseGate) -> &mut Self {
+        self.pause_gate = Some(pause_gate);
+        self
+    }
+
+    /// Returns the size the video is expected to have, from the sizes the
+    /// streams were announced with, or `None` if they are unknown.
+    pub fn expected_output_size(&self) -> Option<(i32, i32)> {
+        let size = if let Some(ref data) = self.select_area_data {
+            let (stream_width, stream_height) = data.stream_size;
+            let crop = videocrop_values(data);
+            (
+                round_to_even(stream_width) - crop.left - crop.right,
+                round_to_even(stream_height) - crop.top - crop.bottom,
+            )
+        } else {
+            // The streams are composited side by side.
+            self.streams
+                .iter()
+                .try_fold((0, 0), |(width, height), stream| {
+                    let (stream_width, stream_height) = stream.size()?;
+                    Some((width + stream_width, height.max(stream_height)))
+                })?
+        };
+
+        if size.0 <= 0 || size.1 <= 0 {
+            return None;
+        }
+
+        Some(output_size(size, self.max_output_height))
+    }
+
     /// Builds the pipeline.
     ///
-    ///                   (If has select_area_data)
-    ///                        |             |
-    ///                        v             v
-    /// pipewiresrc-bin -> videoscale -> videocrop -> queue -> |
-    ///                                                        | -> profile.attach -> filesink
-    ///                               pulsesrc-bin -> queue -> |
+    ///                   (If has select_area_data)                           (If has webcam_overlay)  (If has watermark, text_overlay, or timestamp_overlay)
+    ///                        |             |                                          |                      |
+    ///                        v             v                                          v                      v
+    /// pipewiresrc-bin -> videoscale -> videocrop -> output-videoscale-bin -> webcam-overlay-bin -> overlay-bin -> queue -> |
+    ///                                                                                                                    | -> profile.attach -> filesink
+    ///                                                                                           audiosrc-bin -> queue -> |
+    ///
+    /// The output videoscale bin is always there, as the encoders may require
+    /// even dimensions, which are not guaranteed by the capture. It also
+    /// applies the max output height, if set.
+    ///
+    /// If the audio tracks are separate, each audiosrc gets its own bin, queue,
+    /// and audio encoder instead of being mixed.
+    ///
+    /// If the split limits are enabled, splitmuxsink is used instead of filesink.
+    ///
+    /// If there is a replay buffer, the encoded streams are collected by it
+    /// and discarded by fakesinks instead of being muxed.
+    ///
+    /// If there is a pause gate, it is attached to the src pads of the queues.
     pub fn build(&self) -> Result<gst::Pipeline> {
         tracing::debug!(
             file_path = %self.file_path.display(),
@@ -79,7 +345,19 @@ impl PipelineBuilder {
             streams = ?self.streams,
             record_desktop_audio = ?self.record_desktop_audio,
             record_microphone = ?self.record_microphone,
+            desktop_audio_filters = ?self.desktop_audio_filters,
+            microphone_filters = ?self.microphone_filters,
+            separate_audio_tracks = ?self.separate_audio_tracks,
             select_area_data = ?self.select_area_data,
+            webcam_overlay = ?self.webcam_overlay,
+            watermark = ?self.watermark,
+            text_overlay = ?self.text_overlay,
+            timestamp_position = ?self.timestamp_position,
+            max_output_height = ?self.max_output_height,
+            quality = ?self.quality,
+            split_limits = ?self.split_limits,
+            has_replay_buffer = self.replay_buffer.is_some(),
+            has_pause_gate = self.pause_gate.is_some(),
         );
 
         let pipeline = gst::Pipeline::new();
@@ -89,17 +367,9 @@ impl PipelineBuilder {
         let videoenc_queue = gst::ElementFactory::make("queue")
             .name("kooha-videoenc-queue")
             .build()?;
-        let filesink = gst::ElementFactory::make("filesink")
-            .property(
-                "location",
-                self.file_path
-                    .to_str()
-                    .context("Could not convert file path to string")?,
-            )
-            .build()?;
-        pipeline.add_many([videosrc_bin.upcast_ref(), &videoenc_queue, &filesink])?;
+        pipeline.add_many([videosrc_bin.upcast_ref(), &videoenc_queue])?;
 
-        if let Some(ref data) = self.select_area_data {
+        let mut video_tail = if let Some(ref data) = self.select_area_data {
             let videoscale = gst::ElementFactory::make("videoscale").build()?;
             let videocrop = make_videocrop(data)?;
             pipeline.add_many([&videoscale, &videocrop])?;
@@ -113,55 +383,188 @@ impl PipelineBuilder {
 
             videosrc_bin.link(&videoscale)?;
             videoscale.link_filtered(&videocrop, &videoscale_caps)?;
-            videocrop.link(&videoenc_queue)?;
+
+            videocrop
         } else {
-            videosrc_bin.link(&videoenc_queue)?;
-        }
+            videosrc_bin.clone().upcast()
+        };
 
-        let audioenc_queue = if self.record_desktop_audio || self.record_microphone {
-            debug_assert!(self.profile.supports_audio());
+        let output_videoscale_bin = make_output_videoscale_bin(self.max_output_height)
+            .context("Failed to create output videoscale bin")?;
+        pipeline.add(&output_videoscale_bin)?;
+        video_tail.link(&output_videoscale_bin)?;
+        video_tail = output_videoscale_bin.upcast();
+
+        if let Some(ref webcam_overlay) = self.webcam_overlay {
+            let webcam_overlay_bin = webcam_overlay
+                .make_bin()
+                .context("Failed to create webcam overlay bin")?;
+            pipeline.add(&webcam_overlay_bin)?;
+            video_tail.link(&webcam_overlay_bin)?;
+            video_tail = webcam_overlay_bin.upcast();
+        }
 
-            let pulsesrcs = [
-                self.record_desktop_audio
-                    .then(|| make_pulsesrc(DeviceClass::Sink, "kooha-desktop-audio-src")),
-                self.record_microphone
-                    .then(|| make_pulsesrc(DeviceClass::Source, "kooha-microphone-src")),
-            ];
-            let audiosrc_bin = make_audiosrc_bin(
-                &pulsesrcs
-                    .into_iter()
-                    .flatten()
-                    .collect::<Result<Vec<_>>>()?,
+        if self.watermark.is_some()
+            || self.text_overlay.is_some()
+            || self.timestamp_position.is_some()
+        {
+            let overlay_bin = make_overlay_bin(
+                self.watermark.as_ref(),
+                self.text_overlay.as_deref(),
+                self.timestamp_position,
             )
-            .context("Failed to create audiosrc bin")?;
-            let audioenc_queue = gst::ElementFactory::make("queue")
-                .name("kooha-audioenc-queue")
-                .build()?;
+            .context("Failed to create overlay bin")?;
+            pipeline.add(&overlay_bin)?;
+            video_tail.link(&overlay_bin)?;
+            video_tail = overlay_bin.upcast();
+        }
 
-            pipeline.add_many([audiosrc_bin.upcast_ref(), &audioenc_queue])?;
-            audiosrc_bin.link(&audioenc_queue)?;
+        video_tail.link(&videoenc_queue)?;
 
-            Some(audioenc_queue)
+        let audioenc_queues = if self.record_desktop_audio || self.record_microphone {
+            debug_assert!(self.profile.supports_audio());
+
+            let audiosrcs = [
+                self.record_desktop_audio.then(|| {
+                    let element = device::make_src(
+                        DeviceClass::Sink,
+                        self.desktop_audio_device.as_ref(),
+                        "kooha-desktop-audio-src",
+                    )?;
+                    Ok(AudioSource {
+                        element,
+                        filters: self.desktop_audio_filters,
+                        level_name: DESKTOP_AUDIO_LEVEL_NAME,
+                    })
+                }),
+                self.record_microphone.then(|| {
+                    let element = device::make_src(
+                        DeviceClass::Source,
+                        self.microphone_device.as_ref(),
+                        "kooha-microphone-src",
+                    )?;
+                    Ok(AudioSource {
+                        element,
+                        filters: self.microphone_filters,
+                        level_name: MICROPHONE_LEVEL_NAME,
+                    })
+                }),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Result<Vec<_>>>()?;
+
+            let audiosrc_groups = if self.separate_audio_tracks
+                && audiosrcs.len() > 1
+                && self.profile.supports_multiple_audio_tracks()
+            {
+                audiosrcs.into_iter().map(|src| vec![src]).collect()
+            } else {
+                if self.separate_audio_tracks && audiosrcs.len() > 1 {
+                    tracing::debug!(
+                        "Profile does not support multiple audio tracks, mixing instead"
+                    );
+                }
+
+                vec![audiosrcs]
+            };
+
+            let mut audioenc_queues = Vec::new();
+
+            for (index, audiosrcs) in audiosrc_groups.iter().enumerate() {
+                let audiosrc_bin =
+                    make_audiosrc_bin(&format!("kooha-audiosrc-bin-{}", index), audiosrcs)
+                        .context("Failed to create audiosrc bin")?;
+                let audioenc_queue = gst::ElementFactory::make("queue")
+                    .name(format!("kooha-audioenc-queue-{}", index))
+                    .build()?;
+
+                pipeline.add_many([audiosrc_bin.upcast_ref(), &audioenc_queue])?;
+                audiosrc_bin.link(&audioenc_queue)?;
+
+                audioenc_queues.push(audioenc_queue);
+            }
+
+            audioenc_queues
         } else {
-            None
+            Vec::new()
         };
 
-        self.profile
-            .attach(
+        if let Some(ref pause_gate) = self.pause_gate {
+            for queue in iter::once(&videoenc_queue).chain(&audioenc_queues) {
+                pause_gate.attach(&queue.static_pad("src").unwrap());
+            }
+        }
+
+        if let Some(ref replay_buffer) = self.replay_buffer {
+            self.attach_replay_buffer(&pipeline, &videoenc_queue, &audioenc_queues, replay_buffer)
+        } else {
+            let filesink = if self.split_limits.is_enabled() {
+                debug_assert!(self.profile.supports_splitting());
+
+                make_splitmuxsink(&self.file_path, self.split_limits)?
+            } else {
+                gst::ElementFactory::make("filesink")
+                    .property(
+                        "location",
+                        self.file_path
+                            .to_str()
+                            .context("Could not convert file path to string")?,
+                    )
+                    .build()?
+            };
+            pipeline.add(&filesink)?;
+
+            self.profile.attach(
                 &pipeline,
                 &videoenc_queue,
-                audioenc_queue.as_ref(),
+                &audioenc_queues,
                 &filesink,
+                self.quality,
             )
-            .with_context(|| {
-                format!(
-                    "Failed to attach profile `{}` to pipeline",
-                    self.profile.id()
-                )
-            })?;
+        }
+        .with_context(|| {
+            format!(
+                "Failed to attach profile `{}` to pipeline",
+                self.profile.id()
+            )
+        })?;
 
         Ok(pipeline)
     }
+
+    fn attach_replay_buffer(
+        &self,
+        pipeline: &gst::Pipeline,
+        video_src: &gst::Element,
+        audio_srcs: &[gst::Element],
+        replay_buffer: &ReplayBuffer,
+    ) -> Result<()> {
+        debug_assert!(self.profile.supports_replay_buffer());
+
+        let (videoenc_bin, audioenc_bins) =
+            self.profile
+                .attach_encoders(pipeline, video_src, audio_srcs, self.quality)?;
+
+        for enc_bin in iter::once(&videoenc_bin).chain(&audioenc_bins) {
+            let fakesink = gst::ElementFactory::make("fakesink")
+                .property("sync", false)
+                .property("async", false)
+                .build()?;
+            pipeline.add(&fakesink)?;
+            enc_bin.link(&fakesink)?;
+        }
+
+        replay_buffer.attach(
+            &videoenc_bin.static_pad("src").unwrap(),
+            &audioenc_bins
+                .iter()
+                .map(|bin| bin.static_pad("src").unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        Ok(())
+    }
 }
 
 fn make_pipewiresrc(fd: RawFd, path: &str) -> Result<gst::Element> {
@@ -177,6 +580,57 @@ fn make_pipewiresrc(fd: RawFd, path: &str) -> Result<gst::Element> {
     Ok(src)
 }
 
+/// Creates a splitmuxsink that writes to `<stem>-<sequence>.<extension>`
+/// beside the given file path.
+fn make_splitmuxsink(file_path: &Path, limits: SplitLimits) -> Result<gst::Element> {
+    let max_size = limits.per_file.max_size.unwrap_or(0);
+
+    let splitmuxsink = gst::ElementFactory::make("splitmuxsink")
+        .name("kooha-splitmuxsink")
+        .property("location", split_location(file_path)?)
+        .property("start-index", 1)
+        .property(
+            "max-size-time",
+            limits
+                .per_file
+                .max_duration
+                .unwrap_or(gst::ClockTime::ZERO)
+                .nseconds(),
+        )
+        .property("max-size-bytes", max_size)
+        .property("max-files", limits.max_files.unwrap_or(0))
+        // Encoders may rarely emit keyframes on their own, so request one when
+        // it is time to split. This is only effective without a size limit.
+        .property("send-keyframe-requests", max_size == 0)
+        .build()?;
+
+    Ok(splitmuxsink)
+}
+
+/// Returns the printf-style location pattern used by splitmuxsink.
+fn split_location(file_path: &Path) -> Result<String> {
+    let stem = file_path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .context("Could not convert file stem to string")?;
+
+    let mut file_name = format!("{}-%03d", stem.replace('%', "%%"));
+    if let Some(extension) = file_path.extension() {
+        file_name.push('.');
+        file_name.push_str(
+            extension
+                .to_str()
+                .context("Could not convert file extension to string")?,
+        );
+    }
+
+    let location = file_path.with_file_name(file_name);
+    Ok(location
+        .to_str()
+        .context("Could not convert file path to string")?
+        .to_string())
+}
+
 fn make_videoflip() -> Result<gst::Element> {
     let videoflip = gst::ElementFactory::make("videoflip")
         .property_from_str("video-direction", "auto")
@@ -185,9 +639,34 @@ fn make_videoflip() -> Result<gst::Element> {
     Ok(videoflip)
 }
 
+/// Number of pixels cropped from each edge of the stream.
+struct VideocropValues {
+    top: i32,
+    left: i32,
+    right: i32,
+    bottom: i32,
+}
+
 /// Create a videocrop element that computes the crop from the given coordinates
 /// and size.
 fn make_videocrop(data: &SelectAreaData) -> Result<gst::Element> {
+    let VideocropValues {
+        top,
+        left,
+        right,
+        bottom,
+    } = videocrop_values(data);
+
+    let crop = gst::ElementFactory::make("videocrop")
+        .property("top", top)
+        .property("left", left)
+        .property("right", right)
+        .property("bottom", bottom)
+        .build()?;
+    Ok(crop)
+}
+
+fn videocrop_values(data: &SelectAreaData) -> VideocropValues {
     let SelectAreaData {
         selection,
         paintable_rect,
@@ -227,6 +706,7 @@ fn make_videocrop(data: &SelectAreaData) -> Result<gst::Element> {
 
     tracing::debug!(raw_top_crop, raw_left_crop, raw_right_crop, raw_bottom_crop);
 
+    // x264enc requires even resolution.
     let top_crop = round_to_even_f32(raw_top_crop).clamp(0, *stream_height);
     let left_crop = round_to_even_f32(raw_left_crop).clamp(0, *stream_width);
     let right_crop = round_to_even_f32(raw_right_crop).clamp(0, *stream_width);
@@ -234,14 +714,178 @@ fn make_videocrop(data: &SelectAreaData) -> Result<gst::Element> {
 
     tracing::debug!(top_crop, left_crop, right_crop, bottom_crop);
 
-    // x264enc requires even resolution.
-    let crop = gst::ElementFactory::make("videocrop")
-        .property("top", top_crop)
-        .property("left", left_crop)
-        .property("right", right_crop)
-        .property("bottom", bottom_crop)
-        .build()?;
-    Ok(crop)
+    VideocropValues {
+        top: top_crop,
+        left: left_crop,
+        right: right_crop,
+        bottom: bottom_crop,
+    }
+}
+
+/// Creates a bin that scales the video to the output size, which is only
+/// known once the caps are negotiated.
+///
+/// videoscale -> capsfilter
+fn make_output_videoscale_bin(max_output_height: Option<u32>) -> Result<gst::Bin> {
+    let bin = gst::Bin::builder()
+        .name("kooha-output-videoscale-bin")
+        .build();
+
+    let videoscale = gst::ElementFactory::make("videoscale").build()?;
+    let capsfilter = gst::ElementFactory::make("capsfilter").build()?;
+    bin.add_many([&videoscale, &capsfilter])?;
+    videoscale.link(&capsfilter)?;
+
+    let sink_pad = videoscale.static_pad("sink").unwrap();
+    let capsfilter_weak = capsfilter.downgrade();
+    sink_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(event) = info.event()
+            && let gst::EventView::Caps(caps) = event.view()
+            && let Some(capsfilter) = capsfilter_weak.upgrade()
+        {
+            let res = caps_size(caps.caps()).map(|(width, height)| {
+                let (output_width, output_height) = output_size((width, height), max_output_height);
+
+                tracing::debug!(width, height, output_width, output_height, "Scaling output");
+
+                // The caps are updated before videoscale receives the event,
+                // so it negotiates with the new ones right away.
+                capsfilter.set_property(
+                    "caps",
+                    gst::Caps::builder("video/x-raw")
+                        .field("width", output_width)
+                        .field("height", output_height)
+                        .field("pixel-aspect-ratio", gst::Fraction::new(1, 1))
+                        .build(),
+                );
+            });
+
+            if let Err(err) = res {
+                tracing::warn!("Failed to get video size: {:?}", err);
+            }
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+    bin.add_pad(&gst::GhostPad::with_target(&sink_pad)?)?;
+
+    let src_pad = capsfilter.static_pad("src").unwrap();
+    bin.add_pad(&gst::GhostPad::with_target(&src_pad)?)?;
+
+    Ok(bin)
+}
+
+/// Returns the `(width, height)` of raw video caps.
+pub fn caps_size(caps: &gst::CapsRef) -> Result<(i32, i32)> {
+    let structure = caps.structure(0).context("Caps has no structure")?;
+    let width = structure.get::<i32>("width")?;
+    let height = structure.get::<i32>("height")?;
+    Ok((width, height))
+}
+
+/// Returns the largest even size, with the aspect ratio of the given size,
+/// that fits in a 16:9 box of the max height in the same orientation, e.g.,
+/// 1920x1080 or 1080x1920 for 1080.
+///
+/// The size is never scaled up.
+pub fn output_size((width, height): (i32, i32), max_height: Option<u32>) -> (i32, i32) {
+    let scale = max_height.map_or(1.0, |max_height| {
+        let max_short_side = max_height as f64;
+        let max_long_side = max_short_side * 16.0 / 9.0;
+
+        let (long_side, short_side) = if width >= height {
+            (width, height)
+        } else {
+            (height, width)
+        };
+
+        (max_long_side / long_side as f64)
+            .min(max_short_side / short_side as f64)
+            .min(1.0)
+    });
+
+    let scaled = |number: i32| round_to_even((number as f64 * scale).round() as i32).max(2);
+    (scaled(width), scaled(height))
+}
+
+/// Creates a bin that burns in the watermark, the text, and the timestamp, if
+/// given.
+///
+/// videoconvert -> gdkpixbufoverlay -> textoverlay -> textoverlay (timestamp)
+fn make_overlay_bin(
+    watermark: Option<&Watermark>,
+    text: Option<&str>,
+    timestamp_position: Option<TimestampPosition>,
+) -> Result<gst::Bin> {
+    let bin = gst::Bin::builder().name("kooha-overlay-bin").build();
+
+    let mut elements = vec![gst::ElementFactory::make("videoconvert").build()?];
+
+    if let Some(watermark) = watermark {
+        let (offset_x, offset_y) = match watermark.position {
+            WatermarkPosition::TopLeft => (WATERMARK_MARGIN, WATERMARK_MARGIN),
+            WatermarkPosition::TopRight => (-WATERMARK_MARGIN, WATERMARK_MARGIN),
+            WatermarkPosition::BottomLeft => (WATERMARK_MARGIN, -WATERMARK_MARGIN),
+            WatermarkPosition::BottomRight => (-WATERMARK_MARGIN, -WATERMARK_MARGIN),
+        };
+
+        // Negative offsets are relative to the right and bottom edges.
+        let gdkpixbufoverlay = gst::ElementFactory::make("gdkpixbufoverlay")
+            .property(
+                "location",
+                watermark
+                    .path
+                    .to_str()
+                    .context("Could not convert watermark path to string")?,
+            )
+            .property_from_str("positioning-mode", "pixels-relative-to-edges")
+            .property("offset-x", offset_x)
+            .property("offset-y", offset_y)
+            .property("alpha", watermark.opacity)
+            .build()?;
+        elements.push(gdkpixbufoverlay);
+    }
+
+    if let Some(text) = text {
+        let textoverlay = gst::ElementFactory::make("textoverlay")
+            .name(TEXT_OVERLAY_NAME)
+            .property("text", glib::markup_escape_text(text))
+            .property_from_str("valignment", "bottom")
+            .property_from_str("halignment", "left")
+            .property("shaded-background", true)
+            .build()?;
+        elements.push(textoverlay);
+    }
+
+    if let Some(position) = timestamp_position {
+        let (valignment, halignment) = match position {
+            TimestampPosition::TopLeft => ("top", "left"),
+            TimestampPosition::TopRight => ("top", "right"),
+            TimestampPosition::BottomLeft => ("bottom", "left"),
+            TimestampPosition::BottomRight => ("bottom", "right"),
+        };
+
+        // Monospace keeps the text from jittering as the digits change.
+        let timestamp_overlay = gst::ElementFactory::make("textoverlay")
+            .name(TIMESTAMP_OVERLAY_NAME)
+            .property("font-desc", "Monospace 14")
+            .property_from_str("valignment", valignment)
+            .property_from_str("halignment", halignment)
+            .property("shaded-background", true)
+            .build()?;
+        elements.push(timestamp_overlay);
+    }
+
+    bin.add_many(&elements)?;
+    gst::Element::link_many(&elements)?;
+
+    let sink_pad = elements.first().unwrap().static_pad("sink").unwrap();
+    bin.add_pad(&gst::GhostPad::with_target(&sink_pad)?)?;
+
+    let src_pad = elements.last().unwrap().static_pad("src").unwrap();
+    bin.add_pad(&gst::GhostPad::with_target(&src_pad)?)?;
+
+    Ok(bin)
 }
 
 /// Creates a bin with a src pad for multiple pipewire streams.
@@ -321,58 +965,21 @@ pub fn make_videosrc_bin(
     Ok(bin)
 }
 
-/// Creates a new audio src element with the given name.
+/// Creates a bin with a src pad for audio devices
 ///
-/// If the class is already a source, it will return the device name as is,
-/// otherwise, if it is a sink, it will append `.monitor` to the device name.
-fn make_pulsesrc(class: DeviceClass, element_name: &str) -> Result<gst::Element> {
-    let device = device::find_default(class)?;
-
-    let pulsesrc = gst::ElementFactory::make("pulsesrc")
-        .name(element_name)
-        .property("provide-clock", false)
-        .property("do-timestamp", true)
-        .build()?;
-
-    match class {
-        DeviceClass::Sink => {
-            let pulsesink = device.create_element(None)?;
-            let device_name = pulsesink
-                .property::<Option<String>>("device")
-                .context("No device name")?;
-            ensure!(!device_name.is_empty(), "Empty device name");
-
-            let monitor_name = format!("{}.monitor", device_name);
-            pulsesrc.set_property("device", &monitor_name);
-
-            tracing::debug!("Found desktop audio with name `{}`", monitor_name);
-        }
-        DeviceClass::Source => {
-            device.reconfigure_element(&pulsesrc)?;
-
-            let device_name = pulsesrc
-                .property::<Option<String>>("device")
-                .context("No device name")?;
-            ensure!(!device_name.is_empty(), "Empty device name");
-
-            tracing::debug!("Found microphone with name `{}`", device_name);
-        }
-    }
-
-    Ok(pulsesrc)
-}
-
-/// Creates a bin with a src pad for a pulse audio device
+/// audiosrc1 -> audiorate -> volume -> level -> |
+///                                              |
+/// audiosrc2 -> audiorate -> volume -> level -> | -> audiomixer
+///                                              |
+/// audiosrcn -> audiorate -> volume -> level -> |
 ///
-/// pulsesrc1 -> audiorate -> |
-///                           |
-/// pulsesrc2 -> audiorate -> | -> audiomixer
-///                           |
-/// pulsesrcn -> audiorate -> |
+/// If the source has to be cleaned up, the cleanup elements are inserted
+/// between the volume and the level, so it measures what gets recorded.
 fn make_audiosrc_bin<'a>(
-    pulsesrcs: impl IntoIterator<Item = &'a gst::Element>,
+    name: &str,
+    audiosrcs: impl IntoIterator<Item = &'a AudioSource>,
 ) -> Result<gst::Bin> {
-    let bin = gst::Bin::builder().name("kooha-pulsesrc-bin").build();
+    let bin = gst::Bin::builder().name(name).build();
 
     let caps = gst::Caps::builder_full()
         .structure(
@@ -401,19 +1008,88 @@ fn make_audiosrc_bin<'a>(
     let src_pad = audiomixer_capsfilter.static_pad("src").unwrap();
     bin.add_pad(&gst::GhostPad::with_target(&src_pad)?)?;
 
-    for pulsesrc in pulsesrcs {
+    for audiosrc in audiosrcs {
         let audiorate = gst::ElementFactory::make("audiorate")
             .property("skip-to-first", true)
             .build()?;
+        let volume = gst::ElementFactory::make("volume")
+            .property("volume", audiosrc.filters.volume)
+            .build()?;
+        let level = gst::ElementFactory::make("level")
+            .name(audiosrc.level_name)
+            .property("interval", LEVEL_INTERVAL.nseconds())
+            .property("post-messages", true)
+            .build()?;
 
-        bin.add_many([pulsesrc, &audiorate])?;
-        pulsesrc.link_filtered(&audiorate, &caps)?;
-        audiorate.link_pads(None, &audiomixer, Some("sink_%u"))?;
+        let mut elements = vec![audiorate, volume];
+        if audiosrc.filters.cleanup {
+            let cleanup_elements = make_cleanup_elements().unwrap_or_else(|err| {
+                tracing::warn!(
+                    "Failed to create cleanup elements, recording without cleanup: {:?}",
+                    err
+                );
+                Vec::new()
+            });
+            elements.extend(cleanup_elements);
+        }
+        elements.push(level);
+
+        bin.add(&audiosrc.element)?;
+        bin.add_many(&elements)?;
+        audiosrc.element.link_filtered(&elements[0], &caps)?;
+        gst::Element::link_many(&elements)?;
+        elements
+            .last()
+            .unwrap()
+            .link_pads(None, &audiomixer, Some("sink_%u"))?;
     }
 
     Ok(bin)
 }
 
+/// Whether the microphone can be cleaned up, which requires webrtcdsp from
+/// gst-plugins-bad.
+pub fn is_microphone_cleanup_available() -> bool {
+    gst::ElementFactory::find("webrtcdsp").is_some()
+}
+
+/// Creates the elements that clean up a microphone, in linking order.
+///
+/// audioconvert -> audiocheblimit -> audioconvert -> webrtcdsp -> audioconvert -> audiodynamic
+///
+/// The high-pass filter removes rumble and hum, webrtcdsp suppresses steady
+/// background noise, and the expander acts as a gate for what is left
+/// between words.
+fn make_cleanup_elements() -> Result<Vec<gst::Element>> {
+    let highpass = gst::ElementFactory::make("audiocheblimit")
+        .property_from_str("mode", "high-pass")
+        .property("cutoff", 80.0_f32)
+        .property("poles", 4)
+        .build()?;
+    let webrtcdsp = gst::ElementFactory::make("webrtcdsp")
+        .property("echo-cancel", false)
+        .property("gain-control", false)
+        .property("high-pass-filter", false)
+        .property("noise-suppression", true)
+        .property_from_str("noise-suppression-level", "high")
+        .build()?;
+    let gate = gst::ElementFactory::make("audiodynamic")
+        .property_from_str("mode", "expander")
+        .property_from_str("characteristics", "soft-knee")
+        .property("threshold", 0.01_f32)
+        .property("ratio", 4.0_f32)
+        .build()?;
+
+    Ok(vec![
+        gst::ElementFactory::make("audioconvert").build()?,
+        highpass,
+        gst::ElementFactory::make("audioconvert").build()?,
+        webrtcdsp,
+        gst::ElementFactory::make("audioconvert").build()?,
+        gate,
+    ])
+}
+
 fn round_to_even(number: i32) -> i32 {
     number / 2 * 2
 }
@@ -456,9 +1132,69 @@ mod test {
         assert_even!(round_to_even_f32(6.0));
     }
 
+    #[test]
+    fn split_location_pattern() {
+        assert_eq!(
+            split_location(Path::new("/videos/Kooha-2024-01-01-10-00-00.webm")).unwrap(),
+            "/videos/Kooha-2024-01-01-10-00-00-%03d.webm"
+        );
+        assert_eq!(
+            split_location(Path::new("/videos/100%.mkv")).unwrap(),
+            "/videos/100%%-%03d.mkv"
+        );
+        assert_eq!(
+            split_location(Path::new("/videos/no-extension")).unwrap(),
+            "/videos/no-extension-%03d"
+        );
+    }
+
+    #[test]
+    fn output_size_even() {
+        assert_eq!(output_size((1921, 1081), None), (1920, 1080));
+        assert_eq!(output_size((3001, 1), None), (3000, 2));
+        assert_eq!(output_size((1280, 720), None), (1280, 720));
+    }
+
+    #[test]
+    fn output_size_capped() {
+        assert_eq!(output_size((3840, 2160), Some(1080)), (1920, 1080));
+        assert_eq!(output_size((2160, 3840), Some(720)), (720, 1280));
+        assert_eq!(output_size((3440, 1440), Some(1080)), (1920, 804));
+        assert_eq!(output_size((1366, 768), Some(720)), (1280, 720));
+        // Multi-monitor canvas
+        assert_eq!(output_size((3840, 1080), Some(1080)), (1920, 540));
+    }
+
+    #[test]
+    fn output_size_not_scaled_up() {
+        assert_eq!(output_size((1280, 720), Some(2160)), (1280, 720));
+        assert_eq!(output_size((1000, 1001), Some(1440)), (1000, 1000));
+    }
+
     #[test]
     fn float_round_to_even_f32() {
         assert_even!(round_to_even_f32(5.3));
         assert_even!(round_to_even_f32(2.9));
     }
+
+    #[test]
+    fn limits_reached() {
+        let secs = gst::ClockTime::from_seconds;
+
+        assert!(!Limits::default().is_reached(secs(u32::MAX as u64), u64::MAX));
+
+        let limits = Limits {
+            max_duration: Some(secs(60)),
+            max_size: Some(1_000_000),
+        };
+        assert!(!limits.is_reached(secs(59), 999_999));
+        assert!(limits.is_reached(secs(60), 0));
+        assert!(limits.is_reached(secs(0), 1_000_000));
+
+        let limits = Limits {
+            max_duration: None,
+            max_size: Some(1_000_000),
+        };
+        assert!(!limits.is_reached(secs(3600), 0));
+    }
 }
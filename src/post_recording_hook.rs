@@ -0,0 +1,113 @@
+use std::ffi::{OsStr, OsString};
+
+use anyhow::{Context, Result, bail, ensure};
+use gtk::{
+    gio::{self, prelude::*},
+    glib,
+};
+
+/// Runs the command on each of the recorded files, one after another, with
+/// the path, the duration in seconds, and the profile id appended to its
+/// arguments, and also set as `KOOHA_FILE`, `KOOHA_DURATION`, and
+/// `KOOHA_PROFILE` in its environment.
+///
+/// Fails on the first file that the command could not be run on or that it
+/// exited unsuccessfully for.
+pub async fn run(
+    command: &str,
+    files: &[gio::File],
+    duration: gst::ClockTime,
+    profile_id: &str,
+) -> Result<()> {
+    let duration = format_duration(duration);
+
+    for file in files {
+        let path = file.path().context("Recording has no local path")?;
+
+        let argv = argv(command, path.as_os_str(), &duration, profile_id)?;
+        let argv = argv.iter().map(OsString::as_os_str).collect::<Vec<_>>();
+
+        let launcher = gio::SubprocessLauncher::new(gio::SubprocessFlags::STDERR_PIPE);
+        launcher.setenv("KOOHA_FILE", &path, true);
+        launcher.setenv("KOOHA_DURATION", &duration, true);
+        launcher.setenv("KOOHA_PROFILE", profile_id, true);
+
+        tracing::debug!(?argv, "Running post-recording command");
+
+        let subprocess = launcher
+            .spawn(&argv)
+            .with_context(|| format!("Failed to run `{}`", command))?;
+        let (_, stderr) = subprocess.communicate_utf8_future(None).await?;
+
+        if !subprocess.is_successful() {
+            let stderr = stderr.as_deref().map(str::trim).unwrap_or_default();
+            if subprocess.has_exited() {
+                bail!(
+                    "`{}` exited with status {}: {}",
+                    command,
+                    subprocess.exit_status(),
+                    stderr
+                );
+            } else {
+                bail!("`{}` was terminated: {}", command, stderr);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the arguments of the command, followed by the ones about the file.
+fn argv(command: &str, path: &OsStr, duration: &str, profile_id: &str) -> Result<Vec<OsString>> {
+    let mut argv = glib::shell_parse_argv(command)
+        .with_context(|| format!("Invalid command `{}`", command))?;
+    ensure!(!argv.is_empty(), "Command is empty");
+
+    argv.extend([path.into(), duration.into(), profile_id.into()]);
+
+    Ok(argv)
+}
+
+/// Formats the duration in seconds, e.g., `12.345`.
+fn format_duration(duration: gst::ClockTime) -> String {
+    format!("{:.3}", duration.seconds_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_argv() {
+        assert_eq!(
+            argv(
+                "upload --to 'my server'",
+                OsStr::new("/videos/a b.mp4"),
+                "1.500",
+                "mp4"
+            )
+            .unwrap(),
+            [
+                "upload",
+                "--to",
+                "my server",
+                "/videos/a b.mp4",
+                "1.500",
+                "mp4"
+            ]
+            .map(OsString::from)
+        );
+
+        assert!(argv("upload 'unclosed", OsStr::new("a.mp4"), "1.000", "mp4").is_err());
+        assert!(argv("", OsStr::new("a.mp4"), "1.000", "mp4").is_err());
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(
+            format_duration(gst::ClockTime::from_mseconds(61_250)),
+            "61.250"
+        );
+        assert_eq!(format_duration(gst::ClockTime::ZERO), "0.000");
+    }
+}
@@ -1,14 +1,28 @@
-use std::path::Path;
+use std::{iter, path::Path, rc::Rc};
 
 use adw::{prelude::*, subclass::prelude::*};
 use gettextrs::gettext;
+use gst::prelude::{DeviceExt as _, GstObjectExt as _};
 use gtk::{
-    gio,
-    glib::{self, BoxedAnyObject, clone},
+    gdk, gio,
+    glib::{self, BoxedAnyObject, clone, variant::FromVariant},
 };
 
 use crate::{
-    experimental::Feature, format, item_row::ItemRow, profile::Profile, settings::Settings,
+    device::{self, DeviceClass},
+    experimental::Feature,
+    file_name::{self, FileNameValues},
+    format,
+    i18n::gettext_f,
+    item_row::ItemRow,
+    pipeline,
+    profile::{Profile, ProfileFields},
+    profile_editor::ProfileEditor,
+    settings::{
+        Settings, TimestampOverlay, TimestampPosition, VideoQuality, WatermarkPosition,
+        WebcamPosition, WebcamShape,
+    },
+    webcam,
 };
 
 static BUILTIN_FRAMERATES: &[gst::Fraction] = &[
@@ -30,6 +44,54 @@ const SETTINGS_PROFILE_CHANGED_HANDLER_ID_KEY: &str = "kooha-settings-profile-ch
 /// Used to represent "none" profile in the profiles model
 type NoneProfile = BoxedAnyObject;
 
+/// In the same order as the items of the video quality row
+static VIDEO_QUALITIES: &[VideoQuality] = &[
+    VideoQuality::Low,
+    VideoQuality::Medium,
+    VideoQuality::High,
+    VideoQuality::Lossless,
+    VideoQuality::Bitrate,
+];
+
+/// In the same order as the items of the max output height row, where 0 means
+/// the original size
+static MAX_OUTPUT_HEIGHTS: &[u32] = &[0, 2160, 1440, 1080, 720];
+
+/// In the same order as the items of the webcam position row
+static WEBCAM_POSITIONS: &[WebcamPosition] = &[
+    WebcamPosition::TopLeft,
+    WebcamPosition::TopRight,
+    WebcamPosition::BottomLeft,
+    WebcamPosition::BottomRight,
+];
+
+/// In the same order as the items of the watermark position row
+static WATERMARK_POSITIONS: &[WatermarkPosition] = &[
+    WatermarkPosition::TopLeft,
+    WatermarkPosition::TopRight,
+    WatermarkPosition::BottomLeft,
+    WatermarkPosition::BottomRight,
+];
+
+/// In the same order as the items of the timestamp overlay row
+static TIMESTAMP_OVERLAYS: &[TimestampOverlay] = &[
+    TimestampOverlay::None,
+    TimestampOverlay::WallClock,
+    TimestampOverlay::Elapsed,
+    TimestampOverlay::Both,
+];
+
+/// In the same order as the items of the timestamp position row
+static TIMESTAMP_POSITIONS: &[TimestampPosition] = &[
+    TimestampPosition::TopLeft,
+    TimestampPosition::TopRight,
+    TimestampPosition::BottomLeft,
+    TimestampPosition::BottomRight,
+];
+
+/// In the same order as the items of the webcam shape row
+static WEBCAM_SHAPES: &[WebcamShape] = &[WebcamShape::Rectangle, WebcamShape::Circle];
+
 mod imp {
     use std::cell::OnceCell;
 
@@ -45,11 +107,77 @@ mod imp {
         #[template_child]
         pub(super) delay_row: TemplateChild<adw::SpinRow>,
         #[template_child]
+        pub(super) replay_buffer_duration_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
         pub(super) file_chooser_label: TemplateChild<gtk::Label>,
         #[template_child]
+        pub(super) file_name_template_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) file_name_preview_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
         pub(super) profile_row: TemplateChild<adw::ComboRow>,
         #[template_child]
+        pub(super) user_profile_errors_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub(super) custom_profiles_group: TemplateChild<adw::PreferencesGroup>,
+        #[template_child]
         pub(super) framerate_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) max_output_height_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) video_quality_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) video_bitrate_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) desktop_audio_device_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) microphone_device_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) desktop_audio_volume_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) microphone_volume_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) microphone_cleanup_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub(super) separate_audio_tracks_row: TemplateChild<adw::SwitchRow>,
+        #[template_child]
+        pub(super) webcam_overlay_row: TemplateChild<adw::ExpanderRow>,
+        #[template_child]
+        pub(super) webcam_device_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) webcam_position_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) webcam_shape_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) webcam_size_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) watermark_row: TemplateChild<adw::ActionRow>,
+        #[template_child]
+        pub(super) watermark_position_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) watermark_opacity_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) text_overlay_row: TemplateChild<adw::ExpanderRow>,
+        #[template_child]
+        pub(super) text_overlay_text_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) timestamp_overlay_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) timestamp_position_row: TemplateChild<adw::ComboRow>,
+        #[template_child]
+        pub(super) stop_duration_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) stop_size_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) split_duration_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) split_size_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) split_max_files_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) post_recording_command_row: TemplateChild<adw::EntryRow>,
+
+        pub(super) profile_row_selected_item_notify_handler_id: OnceCell<glib::SignalHandlerId>,
     }
 
     #[glib::object_subclass]
@@ -78,6 +206,44 @@ mod imp {
                     }
                 },
             );
+
+            klass.install_action_async(
+                "preferences.select-watermark",
+                None,
+                |obj, _, _| async move {
+                    let parent = obj.root().map(|r| r.downcast::<gtk::Window>().unwrap());
+                    if let Err(err) = obj.settings().select_watermark(parent.as_ref()).await
+                        && !err
+                            .downcast_ref::<glib::Error>()
+                            .is_some_and(|error| error.matches(gtk::DialogError::Dismissed))
+                    {
+                        tracing::error!("Failed to select watermark: {:?}", err);
+
+                        let toast = adw::Toast::new(&gettext("Failed to set watermark"));
+                        obj.add_toast(toast);
+                    }
+                },
+            );
+
+            klass.install_action("preferences.clear-watermark", None, |obj, _, _| {
+                obj.settings().set_watermark(None);
+            });
+
+            klass.install_action("preferences.new-profile", None, |obj, _, _| {
+                obj.push_profile_editor(None, &ProfileFields::default());
+            });
+
+            klass.install_action("preferences.duplicate-profile", None, |obj, _, _| {
+                if let Some(profile) = obj.settings().profile() {
+                    let mut fields = profile.fields();
+                    fields.name = gettext_f(
+                        // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                        "{name} (Copy)",
+                        &[("name", &fields.name)],
+                    );
+                    obj.push_profile_editor(None, &fields);
+                }
+            });
         }
 
         fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
@@ -93,11 +259,111 @@ mod imp {
             let obj = self.obj();
 
             obj.setup_rows();
+            obj.setup_device_row(
+                &self.desktop_audio_device_row,
+                audio_devices(DeviceClass::Sink),
+                "desktop-audio-device",
+            );
+            obj.setup_device_row(
+                &self.microphone_device_row,
+                audio_devices(DeviceClass::Source),
+                "microphone-device",
+            );
+            obj.setup_device_row(&self.webcam_device_row, webcams(), "webcam-device");
 
             let settings = obj.settings();
             settings
                 .bind_record_delay(&self.delay_row.get(), "value")
                 .build();
+            settings
+                .bind_replay_buffer_duration(&self.replay_buffer_duration_row.get(), "value")
+                .build();
+            settings
+                .bind_file_name_template(&self.file_name_template_row.get(), "text")
+                .build();
+            bind_choice_row(
+                settings.bind_video_quality(&self.video_quality_row.get(), "selected"),
+                VIDEO_QUALITIES,
+            );
+            settings
+                .bind_video_bitrate(&self.video_bitrate_row.get(), "value")
+                .build();
+            bind_choice_row(
+                settings.bind_max_output_height(&self.max_output_height_row.get(), "selected"),
+                MAX_OUTPUT_HEIGHTS,
+            );
+            settings
+                .bind_desktop_audio_volume(&self.desktop_audio_volume_row.get(), "value")
+                .build();
+            settings
+                .bind_microphone_volume(&self.microphone_volume_row.get(), "value")
+                .build();
+            settings
+                .bind_microphone_cleanup(&self.microphone_cleanup_row.get(), "active")
+                .build();
+            self.microphone_cleanup_row
+                .set_sensitive(pipeline::is_microphone_cleanup_available());
+            settings
+                .bind_webcam_overlay(&self.webcam_overlay_row.get(), "enable-expansion")
+                .build();
+            bind_choice_row(
+                settings.bind_webcam_position(&self.webcam_position_row.get(), "selected"),
+                WEBCAM_POSITIONS,
+            );
+            bind_choice_row(
+                settings.bind_webcam_shape(&self.webcam_shape_row.get(), "selected"),
+                WEBCAM_SHAPES,
+            );
+            settings
+                .bind_webcam_size(&self.webcam_size_row.get(), "value")
+                .build();
+            bind_choice_row(
+                settings.bind_watermark_position(&self.watermark_position_row.get(), "selected"),
+                WATERMARK_POSITIONS,
+            );
+            settings
+                .bind_watermark_opacity(&self.watermark_opacity_row.get(), "value")
+                .build();
+            settings
+                .bind_text_overlay(&self.text_overlay_row.get(), "enable-expansion")
+                .build();
+            settings
+                .bind_text_overlay_text(&self.text_overlay_text_row.get(), "text")
+                .build();
+            bind_choice_row(
+                settings.bind_timestamp_overlay(&self.timestamp_overlay_row.get(), "selected"),
+                TIMESTAMP_OVERLAYS,
+            );
+            bind_choice_row(
+                settings.bind_timestamp_position(&self.timestamp_position_row.get(), "selected"),
+                TIMESTAMP_POSITIONS,
+            );
+            self.timestamp_overlay_row
+                .bind_property("selected", &*self.timestamp_position_row, "sensitive")
+                .transform_to(|_, selected: u32| Some(selected != 0))
+                .sync_create()
+                .build();
+            settings
+                .bind_separate_audio_tracks(&self.separate_audio_tracks_row.get(), "active")
+                .build();
+            settings
+                .bind_stop_duration(&self.stop_duration_row.get(), "value")
+                .build();
+            settings
+                .bind_stop_size(&self.stop_size_row.get(), "value")
+                .build();
+            settings
+                .bind_split_duration(&self.split_duration_row.get(), "value")
+                .build();
+            settings
+                .bind_split_size(&self.split_size_row.get(), "value")
+                .build();
+            settings
+                .bind_split_max_files(&self.split_max_files_row.get(), "value")
+                .build();
+            settings
+                .bind_post_recording_command(&self.post_recording_command_row.get(), "text")
+                .build();
             settings.connect_saving_location_changed(clone!(
                 #[weak]
                 obj,
@@ -105,11 +371,49 @@ mod imp {
                     obj.update_file_chooser_label();
                 }
             ));
+            settings.connect_watermark_changed(clone!(
+                #[weak]
+                obj,
+                move |_| {
+                    obj.update_watermark_row();
+                }
+            ));
+            settings.connect_file_name_template_changed(clone!(
+                #[weak]
+                obj,
+                move |_| {
+                    obj.update_file_name_preview_row();
+                }
+            ));
+            settings.connect_capture_mode_changed(clone!(
+                #[weak]
+                obj,
+                move |_| {
+                    obj.update_file_name_preview_row();
+                }
+            ));
+            settings.connect_max_output_height_changed(clone!(
+                #[weak]
+                obj,
+                move |_| {
+                    obj.update_file_name_preview_row();
+                }
+            ));
+            settings.connect_video_quality_changed(clone!(
+                #[weak]
+                obj,
+                move |_| {
+                    obj.update_video_quality_rows();
+                }
+            ));
             settings.connect_profile_changed(clone!(
                 #[weak]
                 obj,
                 move |_| {
                     obj.update_profile_row_selected();
+                    obj.update_video_quality_rows();
+                    obj.update_duplicate_profile_action();
+                    obj.update_file_name_preview_row();
                 }
             ));
             settings.connect_framerate_changed(clone!(
@@ -117,16 +421,21 @@ mod imp {
                 obj,
                 move |_| {
                     obj.update_framerate_row_selected();
+                    obj.update_file_name_preview_row();
                 }
             ));
 
             obj.update_file_chooser_label();
+            obj.update_watermark_row();
             obj.update_profile_row_selected();
+            obj.update_video_quality_rows();
+            obj.update_duplicate_profile_action();
             obj.update_framerate_row_selected();
+            obj.update_file_name_preview_row();
 
             // Load last active value first in `update_*_row` before connecting to
             // the signal to avoid unnecessary updates.
-            self.profile_row.connect_selected_item_notify(clone!(
+            let handler_id = self.profile_row.connect_selected_item_notify(clone!(
                 #[weak]
                 obj,
                 move |row| {
@@ -136,6 +445,9 @@ mod imp {
                     }
                 }
             ));
+            self.profile_row_selected_item_notify_handler_id
+                .set(handler_id)
+                .unwrap();
             self.framerate_row.connect_selected_item_notify(clone!(
                 #[weak]
                 obj,
@@ -184,6 +496,88 @@ impl PreferencesDialog {
             .set_tooltip_text(Some(&saving_location.display().to_string()));
     }
 
+    fn update_watermark_row(&self) {
+        let imp = self.imp();
+
+        let watermark = self.settings().watermark();
+        imp.watermark_row.set_subtitle(
+            &watermark
+                .as_ref()
+                .map_or_else(|| gettext("None"), |path| display_path(path)),
+        );
+        self.action_set_enabled("preferences.clear-watermark", watermark.is_some());
+        imp.watermark_position_row
+            .set_sensitive(watermark.is_some());
+        imp.watermark_opacity_row.set_sensitive(watermark.is_some());
+    }
+
+    fn update_file_name_preview_row(&self) {
+        let imp = self.imp();
+
+        let settings = self.settings();
+        let profile = settings.profile();
+
+        // The selection and the streams are only known once recording, so
+        // the size of the first monitor is used as an example.
+        let monitor_size = gdk::Display::default()
+            .and_then(|display| display.monitors().item(0))
+            .and_downcast::<gdk::Monitor>()
+            .map(|monitor| {
+                let geometry = monitor.geometry();
+                let scale = monitor.scale_factor();
+                (geometry.width() * scale, geometry.height() * scale)
+            });
+        let max_output_height = settings.max_output_height();
+        let values = FileNameValues {
+            profile_id: profile
+                .as_ref()
+                .map(|profile| profile.id().to_string())
+                .unwrap_or_default(),
+            capture_mode: settings.capture_mode(),
+            size: monitor_size.map(|size| {
+                pipeline::output_size(size, (max_output_height > 0).then_some(max_output_height))
+            }),
+            framerate: settings.framerate(),
+        };
+
+        let now = glib::DateTime::now_local().unwrap();
+        let counter = settings.file_name_counter();
+        match file_name::render(&settings.file_name_template(), &values, &now, counter) {
+            Ok(name) => {
+                let extension = profile
+                    .as_ref()
+                    .map_or("", |profile| profile.file_extension());
+                imp.file_name_preview_row
+                    .set_subtitle(&format!("{}.{}", name, extension));
+                imp.file_name_template_row.remove_css_class("error");
+            }
+            Err(err) => {
+                imp.file_name_preview_row.set_subtitle(&err.to_string());
+                imp.file_name_template_row.add_css_class("error");
+            }
+        }
+    }
+
+    fn update_video_quality_rows(&self) {
+        let imp = self.imp();
+
+        let settings = self.settings();
+        let active_profile = settings.profile();
+
+        // GIF, for instance, has no quality to choose.
+        let supports_quality = active_profile.is_some_and(|p| p.supports_quality());
+        imp.video_quality_row.set_sensitive(supports_quality);
+        imp.video_bitrate_row
+            .set_visible(supports_quality && settings.video_quality() == VideoQuality::Bitrate);
+    }
+
+    fn update_duplicate_profile_action(&self) {
+        self.action_set_enabled(
+            "preferences.duplicate-profile",
+            self.settings().profile().is_some(),
+        );
+    }
+
     fn update_profile_row_selected(&self) {
         let imp = self.imp();
 
@@ -194,7 +588,7 @@ impl PreferencesDialog {
         let position = model.iter().position(|item| {
             let item = item.unwrap();
             let profile = profile_from_obj(&item);
-            profile.map(|p| p.id()) == active_profile.map(|p| p.id())
+            profile.map(|p| p.id()) == active_profile.as_ref().map(|p| p.id())
         });
         if let Some(position) = position {
             imp.profile_row.set_selected(position as u32);
@@ -226,6 +620,76 @@ impl PreferencesDialog {
         }
     }
 
+    /// Lists the devices, given as id and name pairs, on the row, with the
+    /// default device first, and keeps the selection in sync with the
+    /// settings key.
+    fn setup_device_row(
+        &self,
+        row: &adw::ComboRow,
+        devices: Vec<(String, String)>,
+        key: &'static str,
+    ) {
+        let settings = self.settings();
+
+        let (mut ids, mut names): (Vec<_>, Vec<_>) =
+            iter::once((String::new(), gettext("Default")))
+                .chain(devices)
+                .unzip();
+
+        // Keep a disconnected device selected instead of silently falling
+        // back to the default one.
+        let active_id = settings.string(key).to_string();
+        if !ids.contains(&active_id) {
+            names.push(gettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "{id} (Unavailable)",
+                &[("id", &active_id)],
+            ));
+            ids.push(active_id);
+        }
+
+        let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+        row.set_model(Some(&gtk::StringList::new(&names)));
+
+        let ids = Rc::new(ids);
+
+        let update_row_selected = clone!(
+            #[weak]
+            row,
+            #[strong]
+            ids,
+            move |settings: &gio::Settings| {
+                let active_id = settings.string(key);
+                if let Some(position) = ids.iter().position(|id| *id == active_id) {
+                    row.set_selected(position as u32);
+                } else {
+                    tracing::warn!(
+                        "Active device `{}` was not found on device model",
+                        active_id
+                    );
+                }
+            }
+        );
+        update_row_selected(&settings);
+        settings.connect_changed(Some(key), move |settings, _| {
+            update_row_selected(settings);
+        });
+
+        // Load last active value first before connecting to the signal to
+        // avoid unnecessary updates.
+        row.connect_selected_notify(clone!(
+            #[strong]
+            settings,
+            move |row| {
+                if let Some(id) = ids.get(row.selected() as usize)
+                    && let Err(err) = settings.set_string(key, id)
+                {
+                    tracing::error!("Failed to set `{}`: {:?}", key, err);
+                }
+            }
+        ));
+    }
+
     fn setup_rows(&self) {
         let imp = self.imp();
 
@@ -303,9 +767,20 @@ impl PreferencesDialog {
                 let item_row = list_item.child().unwrap().downcast::<ItemRow>().unwrap();
 
                 let profile = profile_from_obj(&item);
-                item_row.set_title(
-                    profile.map_or_else(|| gettext("None"), |profile| profile.name().to_string()),
-                );
+                item_row.set_title(profile.map_or_else(
+                    || gettext("None"),
+                    |profile| {
+                        if profile.is_custom() {
+                            gettext_f(
+                                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                                "{name} (Custom)",
+                                &[("name", profile.name())],
+                            )
+                        } else {
+                            profile.name().to_string()
+                        }
+                    },
+                ));
                 item_row.set_shows_warning_icon(
                     profile.is_some_and(|profile| profile.is_experimental()),
                 );
@@ -313,14 +788,22 @@ impl PreferencesDialog {
             |_| {},
         )));
 
+        self.update_profile_model();
+    }
+
+    fn update_profile_model(&self) {
+        let imp = self.imp();
+
+        let settings = self.settings();
+
+        let profiles = Profile::all()
+            .inspect_err(|err| tracing::error!("Failed to load profiles: {:?}", err))
+            .unwrap_or_default();
+
         let active_profile = settings.profile();
         let profile_model = {
-            let profiles = Profile::all()
-                .inspect_err(|err| tracing::error!("Failed to load profiles: {:?}", err))
-                .unwrap_or_default();
-
             let model = gio::ListStore::new::<glib::Object>();
-            model.splice(0, 0, profiles);
+            model.splice(0, 0, &profiles);
 
             if active_profile.is_none() {
                 model.insert(0, &NoneProfile::new(()));
@@ -328,17 +811,177 @@ impl PreferencesDialog {
 
             model
         };
+        let user_profile_errors = Profile::user_profile_errors();
+        imp.user_profile_errors_row
+            .set_subtitle(&glib::markup_escape_text(&user_profile_errors.join("\n")));
+        imp.user_profile_errors_row
+            .set_tooltip_text(Some(&Profile::user_profiles_path().display().to_string()));
+        imp.user_profile_errors_row
+            .set_visible(!user_profile_errors.is_empty());
+
+        let custom_profiles = profiles
+            .iter()
+            .filter(|profile| profile.is_custom())
+            .cloned()
+            .collect::<Vec<_>>();
+        let custom_profiles_model = gio::ListStore::new::<Profile>();
+        custom_profiles_model.splice(0, 0, &custom_profiles);
+        imp.custom_profiles_group.bind_model(
+            Some(&custom_profiles_model),
+            clone!(
+                #[weak(rename_to = obj)]
+                self,
+                #[upgrade_or_panic]
+                move |item| {
+                    let profile = item.downcast_ref::<Profile>().unwrap();
+                    obj.custom_profile_row(profile).upcast()
+                }
+            ),
+        );
+
         let filter = gtk::CustomFilter::new(move |obj| {
             profile_from_obj(obj).is_none_or(|profile| {
                 (Feature::ExperimentalFormats.is_enabled()
                     || !profile.is_experimental()
-                    || active_profile.is_some_and(|active_profile| active_profile == profile))
+                    || active_profile
+                        .as_ref()
+                        .is_some_and(|active_profile| active_profile == profile))
                     && profile.is_available()
             })
         });
         let profile_filter_model = gtk::FilterListModel::new(Some(profile_model), Some(filter));
         imp.profile_row.set_model(Some(&profile_filter_model));
     }
+
+    /// Loads the profiles again after a user profile is saved or deleted.
+    fn reload_profiles(&self) {
+        let imp = self.imp();
+
+        // Swapping the model changes the selected item, but the active
+        // profile must stay the same.
+        let handler_id = imp
+            .profile_row_selected_item_notify_handler_id
+            .get()
+            .unwrap();
+        imp.profile_row.block_signal(handler_id);
+        self.update_profile_model();
+        self.update_profile_row_selected();
+        imp.profile_row.unblock_signal(handler_id);
+
+        self.update_video_quality_rows();
+        self.update_duplicate_profile_action();
+    }
+
+    fn custom_profile_row(&self, profile: &Profile) -> adw::ActionRow {
+        let row = adw::ActionRow::builder()
+            .title(glib::markup_escape_text(profile.name()))
+            .activatable(true)
+            .build();
+
+        if let Some(reason) = profile.unavailable_reason() {
+            row.set_subtitle(&glib::markup_escape_text(&gettext_f(
+                // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                "Unavailable: {reason}",
+                &[("reason", &reason)],
+            )));
+
+            let warning_icon = gtk::Image::from_icon_name("dialog-warning-symbolic");
+            warning_icon.add_css_class("warning");
+            row.add_prefix(&warning_icon);
+        } else {
+            row.set_subtitle(&format!(".{}", profile.file_extension()));
+        }
+
+        row.add_suffix(&gtk::Image::from_icon_name("go-next-symbolic"));
+
+        row.connect_activated(clone!(
+            #[weak(rename_to = obj)]
+            self,
+            #[strong]
+            profile,
+            move |_| {
+                obj.push_profile_editor(Some(profile.id()), &profile.fields());
+            }
+        ));
+
+        row
+    }
+
+    fn push_profile_editor(&self, profile_id: Option<&str>, fields: &ProfileFields) {
+        let editor = ProfileEditor::new(profile_id, fields);
+        editor.connect_changed(clone!(
+            #[weak(rename_to = obj)]
+            self,
+            move |_| {
+                obj.reload_profiles();
+            }
+        ));
+        self.push_subpage(&editor);
+    }
+}
+
+/// Returns the id and name of each audio device of the class.
+fn audio_devices(class: DeviceClass) -> Vec<(String, String)> {
+    device::all(class)
+        .inspect_err(|err| tracing::error!("Failed to load devices: {:?}", err))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|device| match device::id(&device) {
+            Ok(id) => Some((id, device.display_name().into())),
+            Err(err) => {
+                tracing::warn!("Skipping device `{}`: {:?}", device.name(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns the id and name of each webcam.
+fn webcams() -> Vec<(String, String)> {
+    let devices = webcam::all()
+        .inspect_err(|err| tracing::error!("Failed to load webcams: {:?}", err))
+        .unwrap_or_default();
+
+    devices
+        .iter()
+        .map(|device| {
+            let id = webcam::id(device);
+            let name = device.display_name();
+
+            // Identical cameras are told apart by their ids.
+            let is_ambiguous = devices
+                .iter()
+                .filter(|other| other.display_name() == name)
+                .count()
+                > 1;
+            let name = if is_ambiguous {
+                id.clone()
+            } else {
+                name.into()
+            };
+
+            (id, name)
+        })
+        .collect()
+}
+
+/// Binds the selected item of a row whose items are in the same order as
+/// the choices.
+fn bind_choice_row<T>(binding: gio::BindingBuilder<'_>, choices: &'static [T])
+where
+    T: FromVariant + ToVariant + PartialEq,
+{
+    binding
+        .mapping(|variant, _| {
+            let choice = variant.get::<T>()?;
+            let position = choices.iter().position(|c| *c == choice)?;
+            Some((position as u32).to_value())
+        })
+        .set_mapping(|value, _| {
+            let position = value.get::<u32>().ok()?;
+            choices.get(position as usize).map(|c| c.to_variant())
+        })
+        .build();
 }
 
 fn row_factory(
@@ -0,0 +1,309 @@
+use adw::{prelude::*, subclass::prelude::*};
+use anyhow::Result;
+use gettextrs::gettext;
+use gtk::glib::{self, clone, closure_local};
+
+use crate::{
+    i18n::gettext_f,
+    profile::{Profile, ProfileFields},
+};
+
+mod imp {
+    use std::{cell::RefCell, sync::LazyLock};
+
+    use glib::subclass::Signal;
+
+    use super::*;
+
+    #[derive(Default, gtk::CompositeTemplate)]
+    #[template(resource = "/io/github/seadve/Kooha/ui/profile_editor.ui")]
+    pub struct ProfileEditor {
+        #[template_child]
+        pub(super) unavailable_banner: TemplateChild<adw::Banner>,
+        #[template_child]
+        pub(super) name_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) file_extension_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) suggested_max_framerate_row: TemplateChild<adw::SpinRow>,
+        #[template_child]
+        pub(super) videoenc_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) videoenc_error_icon: TemplateChild<gtk::Image>,
+        #[template_child]
+        pub(super) audioenc_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) audioenc_error_icon: TemplateChild<gtk::Image>,
+        #[template_child]
+        pub(super) muxer_row: TemplateChild<adw::EntryRow>,
+        #[template_child]
+        pub(super) muxer_error_icon: TemplateChild<gtk::Image>,
+        #[template_child]
+        pub(super) delete_row: TemplateChild<adw::ButtonRow>,
+
+        /// Id of the user profile being edited, or `None` if it is not
+        /// saved yet.
+        pub(super) profile_id: RefCell<Option<String>>,
+        /// Fields the editor started with, which also carries the ones that
+        /// have no row.
+        pub(super) fields: RefCell<ProfileFields>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for ProfileEditor {
+        const NAME: &'static str = "KoohaProfileEditor";
+        type Type = super::ProfileEditor;
+        type ParentType = adw::NavigationPage;
+
+        fn class_init(klass: &mut Self::Class) {
+            klass.bind_template();
+
+            klass.install_action("profile-editor.save", None, |obj, _, _| {
+                obj.save();
+            });
+
+            klass.install_action("profile-editor.duplicate", None, |obj, _, _| {
+                obj.duplicate();
+            });
+
+            klass.install_action_async("profile-editor.delete", None, |obj, _, _| async move {
+                obj.delete().await;
+            });
+        }
+
+        fn instance_init(obj: &glib::subclass::InitializingObject<Self>) {
+            obj.init_template();
+        }
+    }
+
+    impl ObjectImpl for ProfileEditor {
+        fn constructed(&self) {
+            self.parent_constructed();
+
+            let obj = self.obj();
+
+            for row in [
+                &*self.name_row,
+                &*self.file_extension_row,
+                &*self.videoenc_row,
+                &*self.audioenc_row,
+                &*self.muxer_row,
+            ] {
+                row.connect_changed(clone!(
+                    #[weak]
+                    obj,
+                    move |_| {
+                        obj.update_checks();
+                    }
+                ));
+            }
+        }
+
+        fn signals() -> &'static [Signal] {
+            static SIGNALS: LazyLock<Vec<Signal>> =
+                LazyLock::new(|| vec![Signal::builder("changed").build()]);
+
+            SIGNALS.as_ref()
+        }
+    }
+
+    impl WidgetImpl for ProfileEditor {}
+    impl NavigationPageImpl for ProfileEditor {}
+}
+
+glib::wrapper! {
+    /// Page to edit a user profile, meant to be pushed as a subpage of a
+    /// preferences dialog.
+    pub struct ProfileEditor(ObjectSubclass<imp::ProfileEditor>)
+        @extends gtk::Widget, adw::NavigationPage,
+        @implements gtk::Accessible, gtk::Buildable, gtk::ConstraintTarget;
+}
+
+impl ProfileEditor {
+    /// Creates an editor for the user profile with the id, or for a new
+    /// profile if there is none.
+    pub fn new(profile_id: Option<&str>, fields: &ProfileFields) -> Self {
+        let this = glib::Object::new::<Self>();
+        this.load(profile_id, fields);
+        this
+    }
+
+    /// Emitted when a user profile is saved or deleted.
+    pub fn connect_changed<F>(&self, f: F) -> glib::SignalHandlerId
+    where
+        F: Fn(&Self) + 'static,
+    {
+        self.connect_closure(
+            "changed",
+            true,
+            closure_local!(|obj: &Self| {
+                f(obj);
+            }),
+        )
+    }
+
+    fn load(&self, profile_id: Option<&str>, fields: &ProfileFields) {
+        let imp = self.imp();
+
+        self.set_title(&if profile_id.is_some() {
+            gettext("Edit Format")
+        } else {
+            gettext("New Format")
+        });
+        imp.delete_row.set_visible(profile_id.is_some());
+        imp.profile_id.replace(profile_id.map(String::from));
+
+        // Set before the rows, as changing them reads it again.
+        imp.fields.replace(fields.clone());
+
+        imp.name_row.set_text(&fields.name);
+        imp.file_extension_row.set_text(&fields.file_extension);
+        imp.suggested_max_framerate_row
+            .set_value(fields.suggested_max_framerate.unwrap_or(0.0));
+        imp.videoenc_row.set_text(&fields.videoenc_bin_str);
+        imp.audioenc_row
+            .set_text(fields.audioenc_bin_str.as_deref().unwrap_or_default());
+        imp.muxer_row
+            .set_text(fields.muxer_bin_str.as_deref().unwrap_or_default());
+
+        self.update_checks();
+    }
+
+    /// Returns the fields as they are currently entered.
+    fn fields(&self) -> ProfileFields {
+        let imp = self.imp();
+
+        let mut fields = imp.fields.borrow().clone();
+        fields.name = imp.name_row.text().trim().to_string();
+        fields.file_extension = imp.file_extension_row.text().trim().to_string();
+        fields.suggested_max_framerate =
+            Some(imp.suggested_max_framerate_row.value()).filter(|value| *value > 0.0);
+        fields.videoenc_bin_str = imp.videoenc_row.text().trim().to_string();
+        fields.audioenc_bin_str = optional_text(&imp.audioenc_row);
+        fields.muxer_bin_str = optional_text(&imp.muxer_row);
+        fields
+    }
+
+    fn save(&self) {
+        let imp = self.imp();
+
+        let profile_id = imp.profile_id.borrow().clone();
+        match Profile::save_user_profile(profile_id.as_deref(), &self.fields()) {
+            Ok(profile_id) => {
+                tracing::debug!("Saved user profile `{}`", profile_id);
+
+                self.emit_by_name::<()>("changed", &[]);
+                self.close_with_toast(&gettext("Format saved"));
+            }
+            Err(err) => {
+                tracing::error!("Failed to save user profile: {:?}", err);
+
+                self.add_toast(&gettext("Failed to save format"));
+            }
+        }
+    }
+
+    /// Turns the editor into one for an unsaved copy of the entered fields.
+    fn duplicate(&self) {
+        let mut fields = self.fields();
+        fields.name = gettext_f(
+            // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+            "{name} (Copy)",
+            &[("name", &fields.name)],
+        );
+        self.load(None, &fields);
+    }
+
+    async fn delete(&self) {
+        const CANCEL_RESPONSE_ID: &str = "cancel";
+        const DELETE_RESPONSE_ID: &str = "delete";
+
+        let Some(profile_id) = self.imp().profile_id.borrow().clone() else {
+            return;
+        };
+
+        let dialog = adw::AlertDialog::builder()
+            .heading(gettext("Delete Format?"))
+            .body(gettext("It will be removed from your custom formats."))
+            .close_response(CANCEL_RESPONSE_ID)
+            .default_response(CANCEL_RESPONSE_ID)
+            .build();
+        dialog.add_response(CANCEL_RESPONSE_ID, &gettext("Cancel"));
+
+        dialog.add_response(DELETE_RESPONSE_ID, &gettext("Delete"));
+        dialog.set_response_appearance(DELETE_RESPONSE_ID, adw::ResponseAppearance::Destructive);
+
+        if dialog.choose_future(Some(self)).await != DELETE_RESPONSE_ID {
+            return;
+        }
+
+        match Profile::delete_user_profile(&profile_id) {
+            Ok(()) => {
+                self.emit_by_name::<()>("changed", &[]);
+                self.close_with_toast(&gettext("Format deleted"));
+            }
+            Err(err) => {
+                tracing::error!("Failed to delete user profile: {:?}", err);
+
+                self.add_toast(&gettext("Failed to delete format"));
+            }
+        }
+    }
+
+    fn update_checks(&self) {
+        let imp = self.imp();
+
+        let fields = self.fields();
+        update_error_icon(&imp.videoenc_error_icon, fields.check_videoenc());
+        update_error_icon(&imp.audioenc_error_icon, fields.check_audioenc());
+        update_error_icon(&imp.muxer_error_icon, fields.check_muxer());
+
+        let check = fields.check();
+        self.action_set_enabled("profile-editor.save", check.is_ok());
+
+        if let Err(err) = check {
+            imp.unavailable_banner
+                .set_title(&glib::markup_escape_text(&gettext_f(
+                    // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                    "This format is unavailable: {reason}",
+                    &[("reason", &format!("{:#}", err))],
+                )));
+            imp.unavailable_banner.set_revealed(true);
+        } else {
+            imp.unavailable_banner.set_revealed(false);
+        }
+    }
+
+    fn dialog(&self) -> Option<adw::PreferencesDialog> {
+        self.ancestor(adw::PreferencesDialog::static_type())
+            .map(|dialog| dialog.downcast().unwrap())
+    }
+
+    fn add_toast(&self, title: &str) {
+        if let Some(dialog) = self.dialog() {
+            dialog.add_toast(adw::Toast::new(title));
+        }
+    }
+
+    fn close_with_toast(&self, title: &str) {
+        if let Some(dialog) = self.dialog() {
+            dialog.pop_subpage();
+            dialog.add_toast(adw::Toast::new(title));
+        }
+    }
+}
+
+/// Returns the trimmed text of the row, or `None` if it is empty.
+fn optional_text(row: &adw::EntryRow) -> Option<String> {
+    Some(row.text().trim().to_string()).filter(|text| !text.is_empty())
+}
+
+fn update_error_icon(error_icon: &gtk::Image, res: Result<()>) {
+    match res {
+        Ok(()) => error_icon.set_visible(false),
+        Err(err) => {
+            error_icon.set_tooltip_text(Some(&format!("{:#}", err)));
+            error_icon.set_visible(true);
+        }
+    }
+}
@@ -2,11 +2,15 @@ use std::{
     cell::{Cell, OnceCell, RefCell},
     error,
     ffi::OsStr,
-    fmt,
+    fmt, fs,
     os::fd::{IntoRawFd, OwnedFd},
     path::{Path, PathBuf},
     rc::Rc,
-    time::Duration,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{Context, Error, Result, ensure};
@@ -21,19 +25,35 @@ use crate::{
     application::Application,
     area_selector::AreaSelector,
     cancelled::Cancelled,
+    device::{self, DeviceClass},
     experimental::Feature,
+    file_name::{self, FileNameValues},
+    format,
     help::ContextWithHelp,
     i18n::gettext_f,
-    pipeline::PipelineBuilder,
+    pause_gate::{self, PauseGate},
+    pipeline::{self, Limits, PipelineBuilder, Watermark},
+    profile::{self, Profile, Quality},
+    replay_buffer::ReplayBuffer,
     screencast_portal::{
         CursorMode, PersistMode, Proxy, Session, SourceType, Stream, WindowIdentifier,
     },
-    settings::{CaptureMode, Settings},
+    settings::{CaptureMode, Settings, TimestampOverlay},
     timer::Timer,
+    webcam::{self, WebcamOverlay},
 };
 
 const DURATION_UPDATE_INTERVAL: Duration = Duration::from_millis(200);
 
+/// Peaks at or below this are shown as silence.
+const MIN_PEAK_DB: f64 = -60.0;
+
+const DISK_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+/// Free space, in bytes, that is kept for the muxer to finalize the file.
+const DISK_SPACE_RESERVE: u64 = 64 * 1_000_000;
+/// The disk is shown to be running out of space with this much time left.
+const LOW_DISK_SPACE_TIME_LEFT: gst::ClockTime = gst::ClockTime::from_seconds(5 * 60);
+
 #[derive(Debug)]
 pub struct NoProfileError;
 
@@ -62,9 +82,58 @@ pub enum RecordingState {
     Finished,
 }
 
+impl RecordingState {
+    /// Whether the audio levels are updated from the pipeline.
+    fn has_live_levels(self) -> bool {
+        matches!(self, Self::Recording | Self::Paused)
+    }
+
+    /// Returns the state once the pipeline changed to the given state, or
+    /// `None` if it stays the same.
+    ///
+    /// Only the first time the pipeline plays starts the recording. Pausing
+    /// keeps the pipeline playing, and falling back to another video encoder
+    /// restarts it, which must not resume a paused recording.
+    fn on_pipeline_state_changed(self, pipeline_state: gst::State) -> Option<Self> {
+        match (self, pipeline_state) {
+            (Self::Init | Self::Delayed { .. }, gst::State::Playing) => Some(Self::Recording),
+            _ => None,
+        }
+    }
+}
+
+/// Free space on the filesystem of the output files as of the last check.
+#[derive(Debug, Clone, Copy)]
+struct DiskSpace {
+    checked_at: Instant,
+    /// Size, in bytes, of the files written at the time of the check
+    size: u64,
+    /// In bytes
+    free: u64,
+    /// Bytes written per second, or `None` if nothing is written yet
+    byte_rate: Option<f64>,
+}
+
+impl DiskSpace {
+    /// Returns how long it can still record before only the reserve is left.
+    fn time_left(&self) -> Option<gst::ClockTime> {
+        let byte_rate = self.byte_rate?;
+        let secs_left = self.free.saturating_sub(DISK_SPACE_RESERVE) as f64 / byte_rate;
+        Some(gst::ClockTime::try_from_seconds_f64(secs_left).unwrap_or(gst::ClockTime::MAX))
+    }
+
+    /// Returns whether the reserve could be reached before the next check,
+    /// so recording must be stopped now.
+    fn is_exhausted(&self) -> bool {
+        let next_check_bytes =
+            self.byte_rate.unwrap_or(0.0) * DISK_SPACE_CHECK_INTERVAL.as_secs_f64() * 2.0;
+        (self.free as f64) < DISK_SPACE_RESERVE as f64 + next_check_bytes
+    }
+}
+
 #[derive(Debug, Clone, glib::SharedBoxed)]
 #[shared_boxed_type(name = "KoohaRecordingResult")]
-struct BoxedResult(Rc<Result<(gio::File, gst::ClockTime)>>);
+struct BoxedResult(Rc<Result<(Vec<gio::File>, gst::ClockTime)>>);
 
 mod imp {
     use std::sync::LazyLock;
@@ -81,10 +150,39 @@ mod imp {
         pub(super) state: Cell<RecordingState>,
         #[property(get)]
         pub(super) duration: Cell<gst::ClockTime>,
+        /// Size in bytes of the files written so far
+        #[property(get)]
+        pub(super) size: Cell<u64>,
+        /// Peak of the desktop audio in range 0..=1
+        #[property(get, minimum = 0.0, maximum = 1.0)]
+        pub(super) desktop_audio_level: Cell<f64>,
+        /// Peak of the microphone in range 0..=1
+        #[property(get, minimum = 0.0, maximum = 1.0)]
+        pub(super) microphone_level: Cell<f64>,
 
         pub(super) file: OnceCell<gio::File>,
-
+        /// Files opened by splitmuxsink, from the oldest to the most recent
+        /// one, which may no longer exist due to the max files limit
+        pub(super) split_files: RefCell<Vec<gio::File>>,
+        pub(super) output_path: RefCell<Option<PathBuf>>,
+        pub(super) incomplete_paths: RefCell<Vec<PathBuf>>,
+        pub(super) replay_buffer: OnceCell<ReplayBuffer>,
+        pub(super) profile: OnceCell<Profile>,
+        pub(super) file_name_values: OnceCell<FileNameValues>,
+
+        pub(super) pause_gate: PauseGate,
+        /// Limits at which the recording is stopped on its own
+        pub(super) stop_limits: Cell<Limits>,
+        pub(super) disk_space: Cell<Option<DiskSpace>>,
         pub(super) estimated_final_duration: Cell<Option<gst::ClockTime>>,
+        /// Used to fall back to another video encoder if it fails before
+        /// encoding anything
+        pub(super) videoenc_fallback: OnceCell<(Profile, Quality)>,
+        /// Running time, without the pauses, up to which video has been
+        /// encoded
+        pub(super) encoded_video_time: Arc<Mutex<Option<gst::ClockTime>>>,
+        /// Whether any encoded buffer has been passed on to the muxer
+        pub(super) has_muxed: Arc<AtomicBool>,
 
         pub(super) timer: RefCell<Option<Timer>>,
         pub(super) session: RefCell<Option<Session>>,
@@ -125,6 +223,9 @@ mod imp {
                     Signal::builder("finished")
                         .param_types([BoxedResult::static_type()])
                         .build(),
+                    Signal::builder("warning")
+                        .param_types([String::static_type()])
+                        .build(),
                 ]
             });
 
@@ -161,6 +262,7 @@ impl Recording {
     ) -> Result<()> {
         let imp = self.imp();
         let profile = settings.profile().context(NoProfileError)?;
+        imp.profile.set(profile.clone()).unwrap();
 
         // Setup screencast session
         let restore_token = settings.screencast_restore_token();
@@ -196,13 +298,9 @@ impl Recording {
         imp.session.replace(Some(screencast_session));
         settings.set_screencast_restore_token(&restore_token.unwrap_or_default());
 
-        let file_path = new_recording_path(&settings.saving_location(), profile.file_extension());
-        imp.file.set(gio::File::for_path(&file_path)).unwrap();
-
         let raw_fd = fd.into_raw_fd();
 
         let mut pipeline_builder = PipelineBuilder::new(
-            file_path,
             settings.framerate(),
             profile.clone(),
             raw_fd,
@@ -215,11 +313,128 @@ impl Recording {
             pipeline_builder.select_area_data(data);
         }
 
+        // The overlay is optional, so it is better to record without it than
+        // to not record at all.
+        if settings.webcam_overlay() {
+            match webcam::make_src(&settings.webcam_device()) {
+                Ok(Some(src)) => {
+                    pipeline_builder.webcam_overlay(WebcamOverlay::new(
+                        src,
+                        settings.webcam_position(),
+                        settings.webcam_size() as f64 / 100.0,
+                        settings.webcam_shape(),
+                    ));
+                }
+                Ok(None) => {
+                    tracing::warn!("No webcam found, recording without overlay");
+                    self.emit_warning(&gettext("Camera not found, recording without it"));
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        "Failed to find webcam, recording without overlay: {:?}",
+                        err
+                    );
+                    self.emit_warning(&gettext("Failed to find camera, recording without it"));
+                }
+            }
+        }
+
+        if let Some(path) = settings.watermark() {
+            pipeline_builder.watermark(Watermark {
+                path,
+                position: settings.watermark_position(),
+                opacity: settings.watermark_opacity() as f64 / 100.0,
+            });
+        }
+
+        if settings.text_overlay() {
+            pipeline_builder.text_overlay(&settings.text_overlay_text());
+        }
+
+        let quality = settings.quality();
+        pipeline_builder.quality(quality);
+
+        let max_output_height = settings.max_output_height();
+        if max_output_height > 0 {
+            pipeline_builder.max_output_height(max_output_height);
+        }
+
+        let timestamp_overlay = settings.timestamp_overlay();
+        if timestamp_overlay != TimestampOverlay::None {
+            pipeline_builder.timestamp_overlay(settings.timestamp_position());
+        }
+
         if profile.supports_audio() {
             pipeline_builder.record_desktop_audio(settings.record_desktop_audio());
             pipeline_builder.record_microphone(settings.record_microphone());
+
+            // Saved devices are looked up here so that their errors are not
+            // buried under the generic pipeline error.
+            let desktop_audio_device = settings.desktop_audio_device();
+            if settings.record_desktop_audio() && !desktop_audio_device.is_empty() {
+                pipeline_builder.desktop_audio_device(device::find_by_id(
+                    DeviceClass::Sink,
+                    &desktop_audio_device,
+                )?);
+            }
+
+            let microphone_device = settings.microphone_device();
+            if settings.record_microphone() && !microphone_device.is_empty() {
+                pipeline_builder.microphone_device(device::find_by_id(
+                    DeviceClass::Source,
+                    &microphone_device,
+                )?);
+            }
+
+            pipeline_builder.desktop_audio_volume(settings.desktop_audio_volume() as f64 / 100.0);
+            pipeline_builder.microphone_volume(settings.microphone_volume() as f64 / 100.0);
+            pipeline_builder.microphone_cleanup(settings.microphone_cleanup());
+            pipeline_builder.separate_audio_tracks(settings.separate_audio_tracks());
         }
 
+        if settings.replay_buffer() {
+            ensure!(
+                profile.supports_replay_buffer(),
+                ContextWithHelp::new(
+                    gettext("Failed to start replay buffer"),
+                    gettext(
+                        "The selected format does not support it. Select another format in the preferences."
+                    ),
+                )
+            );
+
+            let replay_buffer = ReplayBuffer::new(
+                profile.clone(),
+                gst::ClockTime::from_seconds(settings.replay_buffer_duration() as u64),
+            );
+            pipeline_builder.replay_buffer(replay_buffer.clone());
+            imp.replay_buffer.set(replay_buffer).unwrap();
+        } else {
+            imp.stop_limits.set(settings.stop_limits());
+
+            if profile.supports_splitting() {
+                pipeline_builder.split_limits(settings.split_limits());
+            }
+        }
+
+        pipeline_builder.pause_gate(imp.pause_gate.clone());
+
+        // Named once everything that the name may depend on is known.
+        let file_name_values = FileNameValues {
+            profile_id: profile.id().to_string(),
+            capture_mode: settings.capture_mode(),
+            size: pipeline_builder.expected_output_size(),
+            framerate: settings.framerate(),
+        };
+        let file_path = imp.output_path.take().unwrap_or_else(|| {
+            new_recording_path(settings, &file_name_values, profile.file_extension())
+        });
+        imp.file.set(gio::File::for_path(&file_path)).unwrap();
+        imp.file_name_values.set(file_name_values).unwrap();
+        pipeline_builder.file_path(file_path.clone());
+
+        profile.probe_videoenc_candidates(quality).await;
+
         // Build pipeline
         let pipeline = pipeline_builder.build().with_context(|| {
             ContextWithHelp::new(
@@ -229,6 +444,46 @@ impl Recording {
         })?;
         imp.pipeline.set(pipeline.clone()).unwrap();
 
+        if let Some(element) = pipeline.by_name(pipeline::TIMESTAMP_OVERLAY_NAME) {
+            attach_timestamp_updater(&element, timestamp_overlay, imp.pause_gate.clone());
+        }
+
+        if let Some(videoenc_bin) = pipeline.by_name(profile::VIDEOENC_BIN_NAME) {
+            let encoded_video_time = Arc::clone(&imp.encoded_video_time);
+            videoenc_bin.static_pad("src").unwrap().add_probe(
+                gst::PadProbeType::BUFFER,
+                move |pad, info| {
+                    if let Some(running_time) = info
+                        .buffer()
+                        .and_then(|buffer| pause_gate::buffer_running_time(pad, buffer))
+                    {
+                        let mut encoded_video_time = encoded_video_time.lock().unwrap();
+                        // Encoders may reorder the frames.
+                        if encoded_video_time.is_none_or(|time| time < running_time) {
+                            encoded_video_time.replace(running_time);
+                        }
+                    }
+                    gst::PadProbeReturn::Ok
+                },
+            );
+            imp.videoenc_fallback
+                .set((profile.clone(), quality))
+                .unwrap();
+        }
+        for enc_bin in pipeline.children().into_iter().filter(|child| {
+            child.name() == profile::VIDEOENC_BIN_NAME
+                || child.name().starts_with(profile::AUDIOENC_BIN_NAME_PREFIX)
+        }) {
+            let has_muxed = Arc::clone(&imp.has_muxed);
+            enc_bin.static_pad("src").unwrap().add_probe(
+                gst::PadProbeType::BUFFER | gst::PadProbeType::BUFFER_LIST,
+                move |_, _| {
+                    has_muxed.store(true, Ordering::Relaxed);
+                    gst::PadProbeReturn::Remove
+                },
+            );
+        }
+
         // Setup and run timer
         let timer = Timer::new(
             settings.record_delay(),
@@ -262,12 +517,21 @@ impl Recording {
                 #[upgrade_or_panic]
                 move || {
                     obj.update_duration();
+                    obj.update_size();
+                    obj.update_disk_space();
                     obj.update_flushing_progress();
+                    obj.stop_if_limit_reached();
                     glib::ControlFlow::Continue
                 }
             ),
         )));
 
+        // Unmarked once the muxer has finalized the file, so anything left
+        // marked after a crash can be offered for recovery on next startup.
+        if !self.is_replay_buffer() {
+            self.mark_incomplete(&file_path);
+        }
+
         pipeline
             .set_state(gst::State::Playing)
             .context("Failed to initialize pipeline state to playing")
@@ -283,17 +547,72 @@ impl Recording {
         Ok(())
     }
 
+    /// Overrides the path where the recording will be saved, instead of
+    /// generating one in the saving location.
+    ///
+    /// This must be called before `start`.
+    pub fn set_output_path(&self, path: PathBuf) {
+        debug_assert!(matches!(self.state(), RecordingState::Init));
+
+        self.imp().output_path.replace(Some(path));
+    }
+
+    /// Returns the profile that is recorded with, once started.
+    pub fn profile(&self) -> Option<Profile> {
+        self.imp().profile.get().cloned()
+    }
+
+    /// Whether the recording only keeps the most recent part in memory,
+    /// which is written to a file with `save_replay`.
+    pub fn is_replay_buffer(&self) -> bool {
+        self.imp().replay_buffer.get().is_some()
+    }
+
+    /// Writes the replay buffer to a new file in the saving location without
+    /// stopping the recording.
+    pub async fn save_replay(&self, settings: &Settings) -> Result<(gio::File, gst::ClockTime)> {
+        let replay_buffer = self
+            .imp()
+            .replay_buffer
+            .get()
+            .context("Recording has no replay buffer")?;
+
+        ensure!(
+            matches!(
+                self.state(),
+                RecordingState::Recording | RecordingState::Paused
+            ),
+            "Replay can only be saved while recording"
+        );
+
+        let path = new_recording_path(
+            settings,
+            self.imp().file_name_values.get().unwrap(),
+            replay_buffer.profile().file_extension(),
+        );
+        let duration = replay_buffer.save(&path).await.with_context(|| {
+            ContextWithHelp::new(
+                gettext("Failed to save replay"),
+                gettext("Make sure that the saving location exists and is accessible."),
+            )
+        })?;
+
+        Ok((gio::File::for_path(path), duration))
+    }
+
     pub fn pause(&self) -> Result<()> {
         ensure!(
             matches!(self.state(), RecordingState::Recording),
             "Recording can only be paused from recording state"
         );
 
-        self.pipeline()
-            .set_state(gst::State::Paused)
-            .context("Failed to set pipeline state to paused")?;
+        let running_time = self
+            .pipeline()
+            .current_running_time()
+            .context("Pipeline has no running time")?;
+        self.imp().pause_gate.pause(running_time);
 
-        // TODO Handle effect on estimated final duration
+        self.set_state(RecordingState::Paused);
 
         Ok(())
     }
@@ -304,9 +623,13 @@ impl Recording {
             "Recording can only be resumed from paused state"
         );
 
-        self.pipeline()
-            .set_state(gst::State::Playing)
-            .context("Failed to set pipeline state to playing from paused")?;
+        let running_time = self
+            .pipeline()
+            .current_running_time()
+            .context("Pipeline has no running time")?;
+        self.imp().pause_gate.resume(running_time);
+
+        self.set_state(RecordingState::Recording);
 
         Ok(())
     }
@@ -325,9 +648,11 @@ impl Recording {
 
         let pipeline = self.pipeline();
 
+        // The duration is no longer updated while flushing, so it is left at
+        // what is expected to be in the output.
+        self.update_duration();
         self.set_state(RecordingState::Flushing { progress: 0 });
-        imp.estimated_final_duration
-            .set(pipeline.current_running_time());
+        imp.estimated_final_duration.set(Some(self.duration()));
         self.update_flushing_progress();
 
         tracing::debug!("Sending eos event to pipeline");
@@ -359,6 +684,8 @@ impl Recording {
             source_id.remove();
         }
 
+        self.unmark_all_incomplete();
+
         // HACK we need to return before calling this to avoid a `BorrowMutError` when
         // `Window` tried to take the `recording` on finished callback while `recording`
         // is borrowed to call `cancel`.
@@ -373,7 +700,7 @@ impl Recording {
 
     pub fn connect_finished<F>(&self, f: F) -> glib::SignalHandlerId
     where
-        F: Fn(&Self, &Result<(gio::File, gst::ClockTime)>) + 'static,
+        F: Fn(&Self, &Result<(Vec<gio::File>, gst::ClockTime)>) + 'static,
     {
         self.connect_closure(
             "finished",
@@ -384,6 +711,25 @@ impl Recording {
         )
     }
 
+    /// Called when something went wrong that the recording continues
+    /// without, with a message to show to the user.
+    pub fn connect_warning<F>(&self, f: F) -> glib::SignalHandlerId
+    where
+        F: Fn(&Self, &str) + 'static,
+    {
+        self.connect_closure(
+            "warning",
+            true,
+            closure_local!(|obj: &Self, message: String| {
+                f(obj, &message);
+            }),
+        )
+    }
+
+    fn emit_warning(&self, message: &str) {
+        self.emit_by_name::<()>("warning", &[&message]);
+    }
+
     fn set_state(&self, state: RecordingState) {
         tracing::trace!("Recording state changed to {:?}", state);
 
@@ -391,10 +737,35 @@ impl Recording {
             return;
         }
 
+        // The pipeline keeps playing while paused, so the levels stay live
+        // then, which shows whether the sources still pick anything up.
+        if !state.has_live_levels() {
+            self.set_audio_level(pipeline::DESKTOP_AUDIO_LEVEL_NAME, 0.0);
+            self.set_audio_level(pipeline::MICROPHONE_LEVEL_NAME, 0.0);
+        }
+
         self.imp().state.replace(state);
         self.notify_state();
     }
 
+    fn set_audio_level(&self, level_name: &str, level: f64) {
+        let imp = self.imp();
+
+        match level_name {
+            pipeline::DESKTOP_AUDIO_LEVEL_NAME => {
+                if imp.desktop_audio_level.replace(level) != level {
+                    self.notify_desktop_audio_level();
+                }
+            }
+            pipeline::MICROPHONE_LEVEL_NAME => {
+                if imp.microphone_level.replace(level) != level {
+                    self.notify_microphone_level();
+                }
+            }
+            _ => tracing::warn!("Unknown level element `{}`", level_name),
+        }
+    }
+
     fn file(&self) -> &gio::File {
         self.imp()
             .file
@@ -409,7 +780,78 @@ impl Recording {
             .expect("pipeline not set, make sure to start recording first")
     }
 
-    fn set_finished(&self, res: Result<(gio::File, gst::ClockTime)>) {
+    /// Limits at which the recording stops on its own.
+    pub fn stop_limits(&self) -> Limits {
+        self.imp().stop_limits.get()
+    }
+
+    pub fn has_text_overlay(&self) -> bool {
+        self.imp()
+            .pipeline
+            .get()
+            .is_some_and(|pipeline| pipeline.by_name(pipeline::TEXT_OVERLAY_NAME).is_some())
+    }
+
+    /// Changes the burned-in text, if the recording has a text overlay.
+    pub fn set_overlay_text(&self, text: &str) {
+        let Some(pipeline) = self.imp().pipeline.get() else {
+            return;
+        };
+
+        if let Some(textoverlay) = pipeline.by_name(pipeline::TEXT_OVERLAY_NAME) {
+            textoverlay.set_property("text", glib::markup_escape_text(text));
+        }
+    }
+
+    /// Returns all the files written by the recording.
+    ///
+    /// When the recording is split, this only includes the files that were not
+    /// deleted to satisfy the max files limit.
+    /// This is empty for replay buffers, as their files are written on
+    /// `save_replay`.
+    fn output_files(&self) -> Vec<gio::File> {
+        if self.is_replay_buffer() {
+            return Vec::new();
+        }
+
+        let split_files = self.imp().split_files.take();
+
+        if split_files.is_empty() {
+            return vec![self.file().clone()];
+        }
+
+        split_files
+            .into_iter()
+            .filter(|file| file.query_exists(gio::Cancellable::NONE))
+            .collect()
+    }
+
+    /// Remembers the path as being written to until it is unmarked.
+    fn mark_incomplete(&self, path: &Path) {
+        // The default settings are used, as the given ones may be delayed.
+        Settings::default().add_incomplete_recording(path);
+        self.imp()
+            .incomplete_paths
+            .borrow_mut()
+            .push(path.to_path_buf());
+    }
+
+    fn unmark_incomplete(&self, path: &Path) {
+        Settings::default().remove_incomplete_recording(path);
+        self.imp()
+            .incomplete_paths
+            .borrow_mut()
+            .retain(|p| p != path);
+    }
+
+    fn unmark_all_incomplete(&self) {
+        let settings = Settings::default();
+        for path in self.imp().incomplete_paths.take() {
+            settings.remove_incomplete_recording(&path);
+        }
+    }
+
+    fn set_finished(&self, res: Result<(Vec<gio::File>, gst::ClockTime)>) {
         self.set_state(RecordingState::Finished);
 
         let result = BoxedResult(Rc::new(res));
@@ -430,11 +872,17 @@ impl Recording {
     fn update_duration(&self) {
         let imp = self.imp();
 
+        if matches!(self.state(), RecordingState::Flushing { .. }) {
+            return;
+        }
+
         let clock_time = imp
             .pipeline
             .get()
-            .and_then(|pipeline| pipeline.query_position::<gst::ClockTime>())
-            .unwrap_or(gst::ClockTime::ZERO);
+            .and_then(|pipeline| pipeline.current_running_time())
+            .map_or(gst::ClockTime::ZERO, |running_time| {
+                imp.pause_gate.output_time(running_time)
+            });
 
         if clock_time == self.duration() {
             return;
@@ -444,6 +892,128 @@ impl Recording {
         self.notify_duration();
     }
 
+    fn update_size(&self) {
+        let imp = self.imp();
+
+        if self.is_replay_buffer() {
+            return;
+        }
+
+        let split_files = imp.split_files.borrow();
+        let files = if split_files.is_empty() {
+            imp.file.get().into_iter().collect::<Vec<_>>()
+        } else {
+            split_files.iter().collect()
+        };
+
+        // Files deleted due to the max files limit are skipped, as they
+        // have no metadata.
+        let size = files
+            .into_iter()
+            .filter_map(|file| file.path())
+            .filter_map(|path| fs::metadata(path).ok())
+            .map(|metadata| metadata.len())
+            .sum::<u64>();
+
+        if size == self.size() {
+            return;
+        }
+
+        imp.size.set(size);
+        self.notify_size();
+    }
+
+    /// Estimated time that can still be recorded until the disk is full, or
+    /// `None` if it is not known yet.
+    pub fn disk_space_time_left(&self) -> Option<gst::ClockTime> {
+        self.imp()
+            .disk_space
+            .get()
+            .and_then(|disk_space| disk_space.time_left())
+    }
+
+    /// Whether the disk is about to be full, at which point the recording is
+    /// stopped on its own.
+    pub fn is_low_on_disk_space(&self) -> bool {
+        self.disk_space_time_left()
+            .is_some_and(|time_left| time_left < LOW_DISK_SPACE_TIME_LEFT)
+    }
+
+    fn update_disk_space(&self) {
+        let imp = self.imp();
+
+        if self.is_replay_buffer()
+            || !matches!(
+                self.state(),
+                RecordingState::Recording | RecordingState::Paused
+            )
+        {
+            return;
+        }
+
+        let prev = imp.disk_space.get();
+        if prev.is_some_and(|prev| prev.checked_at.elapsed() < DISK_SPACE_CHECK_INTERVAL) {
+            return;
+        }
+
+        let Some(dir) = self.file().parent() else {
+            return;
+        };
+
+        let free = match dir
+            .query_filesystem_info(gio::FILE_ATTRIBUTE_FILESYSTEM_FREE, gio::Cancellable::NONE)
+        {
+            Ok(info) => info.attribute_uint64(gio::FILE_ATTRIBUTE_FILESYSTEM_FREE),
+            Err(err) => {
+                tracing::warn!("Failed to query filesystem info: {:?}", err);
+                return;
+            }
+        };
+
+        // Nothing is written while paused, and older files may have been
+        // deleted when splitting, so the previous rate is kept then.
+        let size = self.size();
+        let byte_rate = prev
+            .and_then(|prev| {
+                let secs = prev.checked_at.elapsed().as_secs_f64();
+                Some((size.checked_sub(prev.size)? as f64 / secs).max(0.0))
+            })
+            .filter(|byte_rate| *byte_rate > 0.0)
+            .or_else(|| prev.and_then(|prev| prev.byte_rate));
+
+        let disk_space = DiskSpace {
+            checked_at: Instant::now(),
+            size,
+            free,
+            byte_rate,
+        };
+        imp.disk_space.set(Some(disk_space));
+
+        if disk_space.is_exhausted() {
+            tracing::warn!(?disk_space, "Stopping as the disk is almost full");
+
+            self.stop();
+        }
+    }
+
+    fn stop_if_limit_reached(&self) {
+        if !matches!(self.state(), RecordingState::Recording) {
+            return;
+        }
+
+        let stop_limits = self.imp().stop_limits.get();
+        if stop_limits.is_reached(self.duration(), self.size()) {
+            tracing::debug!(
+                duration = ?self.duration(),
+                size = self.size(),
+                ?stop_limits,
+                "Stop limit reached"
+            );
+
+            self.stop();
+        }
+    }
+
     fn update_flushing_progress(&self) {
         let imp = self.imp();
 
@@ -458,7 +1028,12 @@ impl Recording {
             .estimated_final_duration
             .get()
             .map_or(0, |estimated_final_duration| {
-                let progress_percent = self.duration().nseconds() as f64
+                let encoded_video_time = imp
+                    .encoded_video_time
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(gst::ClockTime::ZERO);
+                let progress_percent = encoded_video_time.nseconds() as f64
                     / estimated_final_duration.nseconds() as f64
                     * 100.0;
                 (progress_percent.round() as u8).clamp(0, 100)
@@ -471,6 +1046,69 @@ impl Recording {
         self.set_state(RecordingState::Flushing { progress });
     }
 
+    /// Returns the bin of the video encoder, if the message is from it.
+    fn videoenc_bin_of(&self, message: &gst::Message) -> Option<gst::Bin> {
+        let videoenc_bin = self
+            .pipeline()
+            .by_name(profile::VIDEOENC_BIN_NAME)
+            .and_downcast::<gst::Bin>()?;
+        message
+            .src()
+            .is_some_and(|src| src.has_as_ancestor(&videoenc_bin))
+            .then_some(videoenc_bin)
+    }
+
+    /// Restarts the pipeline with the next candidate of the video encoder if
+    /// the error is from the current one and nothing has reached the muxer
+    /// yet, e.g., a hardware encoder that failed to initialize, returning
+    /// whether it did.
+    ///
+    /// The pipeline is only brought back to `Ready`, so that the connection
+    /// to the screencast is kept. The file is reopened, which is only fine as
+    /// nothing could have been written to it yet.
+    fn try_fall_back_videoenc(&self, message: &gst::Message) -> bool {
+        let imp = self.imp();
+
+        let Some((profile, quality)) = imp.videoenc_fallback.get() else {
+            return false;
+        };
+
+        if matches!(self.state(), RecordingState::Flushing { .. })
+            || imp.has_muxed.load(Ordering::Relaxed)
+        {
+            return false;
+        }
+
+        let Some(videoenc_bin) = self.videoenc_bin_of(message) else {
+            return false;
+        };
+
+        let pipeline = self.pipeline();
+        if let Err(err) = pipeline.set_state(gst::State::Ready) {
+            tracing::warn!("Failed to stop pipeline for videoenc fallback: {:?}", err);
+            return false;
+        }
+
+        match profile.fall_back_videoenc(&videoenc_bin, *quality) {
+            Ok(true) => {}
+            Ok(false) => return false,
+            Err(err) => {
+                tracing::warn!("Failed to fall back to another videoenc: {:?}", err);
+                return false;
+            }
+        }
+
+        if let Err(err) = pipeline.set_state(gst::State::Playing) {
+            tracing::warn!(
+                "Failed to restart pipeline after videoenc fallback: {:?}",
+                err
+            );
+            return false;
+        }
+
+        true
+    }
+
     fn handle_bus_message(&self, message: &gst::Message) -> glib::ControlFlow {
         use gst::MessageView;
 
@@ -480,6 +1118,10 @@ impl Recording {
             MessageView::Error(e) => {
                 tracing::debug!(state = ?self.state(), "Received error at bus");
 
+                if self.try_fall_back_videoenc(message) {
+                    return glib::ControlFlow::Continue;
+                }
+
                 if let Err(err) = self.pipeline().set_state(gst::State::Null) {
                     tracing::warn!("Failed to stop pipeline on error: {:?}", err);
                 }
@@ -496,7 +1138,17 @@ impl Recording {
                     .context(e.debug().unwrap_or_else(|| "<no debug>".into()))
                     .context(gettext("An error occurred while recording"));
 
-                let error = if e.error().matches(gst::ResourceError::OpenWrite) {
+                let error = if self.videoenc_bin_of(message).is_some() {
+                    error.context(ContextWithHelp::new(
+                        gettext("The video encoder stopped working"),
+                        gettext("Try another format or quality in the preferences."),
+                    ))
+                } else if e.error().matches(gst::ResourceError::NoSpaceLeft) {
+                    error.context(ContextWithHelp::new(
+                        gettext("The disk is full"),
+                        gettext("Free up some space or change the saving location."),
+                    ))
+                } else if e.error().matches(gst::ResourceError::OpenWrite) {
                     error.context(ContextWithHelp::new(
                         gettext_f(
                             // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
@@ -524,7 +1176,6 @@ impl Recording {
 
                 self.set_state(RecordingState::Flushing { progress: 100 });
 
-                self.update_duration();
                 let duration = self.duration();
 
                 if let Err(err) = self.pipeline().set_state(gst::State::Null) {
@@ -537,7 +1188,8 @@ impl Recording {
                     source_id.remove();
                 }
 
-                self.set_finished(Ok((self.file().clone(), duration)));
+                self.unmark_all_incomplete();
+                self.set_finished(Ok((self.output_files(), duration)));
 
                 glib::ControlFlow::Break
             }
@@ -567,12 +1219,9 @@ impl Recording {
                     new_state,
                 );
 
-                let state = match new_state {
-                    gst::State::Paused => RecordingState::Paused,
-                    gst::State::Playing => RecordingState::Recording,
-                    _ => return glib::ControlFlow::Continue,
-                };
-                self.set_state(state);
+                if let Some(state) = self.state().on_pipeline_state_changed(new_state) {
+                    self.set_state(state);
+                }
 
                 glib::ControlFlow::Continue
             }
@@ -587,6 +1236,52 @@ impl Recording {
 
                 glib::ControlFlow::Continue
             }
+            MessageView::Element(e) => {
+                let Some(structure) = e.structure() else {
+                    return glib::ControlFlow::Continue;
+                };
+
+                match structure.name().as_str() {
+                    "splitmuxsink-fragment-opened" => match structure.get::<&str>("location") {
+                        Ok(location) => {
+                            tracing::debug!("Split file `{}` opened", location);
+
+                            self.mark_incomplete(Path::new(location));
+                            push_split_file(
+                                &mut imp.split_files.borrow_mut(),
+                                gio::File::for_path(location),
+                            );
+                        }
+                        Err(err) => tracing::warn!("Split file has no location: {:?}", err),
+                    },
+                    "splitmuxsink-fragment-closed" => match structure.get::<&str>("location") {
+                        Ok(location) => {
+                            tracing::debug!("Split file `{}` closed", location);
+
+                            self.unmark_incomplete(Path::new(location));
+                        }
+                        Err(err) => tracing::warn!("Split file has no location: {:?}", err),
+                    },
+                    "level" => match structure.get::<&glib::ValueArray>("peak") {
+                        Ok(peaks) => {
+                            let peak_db = peaks
+                                .iter()
+                                .filter_map(|peak| peak.get::<f64>().ok())
+                                .fold(f64::NEG_INFINITY, f64::max);
+
+                            if let Some(src) = message.src()
+                                && self.state().has_live_levels()
+                            {
+                                self.set_audio_level(&src.name(), peak_to_level(peak_db));
+                            }
+                        }
+                        Err(err) => tracing::warn!("Level has no peak: {:?}", err),
+                    },
+                    _ => {}
+                }
+
+                glib::ControlFlow::Continue
+            }
             MessageView::Warning(w) => {
                 tracing::warn!("Received warning message on bus: {:?}", w);
                 glib::ControlFlow::Continue
@@ -672,14 +1367,227 @@ async fn new_screencast_session(
     Ok((session, streams, restore_token, fd))
 }
 
-fn new_recording_path(saving_location: &Path, extension: impl AsRef<OsStr>) -> PathBuf {
-    let file_name = glib::DateTime::now_local()
-        .expect("You are somehow on year 9999")
-        .format("Kooha-%F-%H-%M-%S")
-        .expect("Invalid format string");
+/// Returns a path in the saving location named after the file name
+/// template, or after the default one if it is invalid, that does not
+/// overwrite an existing file.
+fn new_recording_path(
+    settings: &Settings,
+    values: &FileNameValues,
+    extension: impl AsRef<OsStr>,
+) -> PathBuf {
+    let now = glib::DateTime::now_local().expect("You are somehow on year 9999");
+
+    let template = settings.file_name_template();
+    let counter = if file_name::uses_counter(&template) {
+        // The default settings are used, as the given ones may be delayed.
+        Settings::default().next_file_name_counter()
+    } else {
+        0
+    };
+
+    let file_name = file_name::render(&template, values, &now, counter).unwrap_or_else(|err| {
+        tracing::warn!("Falling back to the default file name template: {:?}", err);
+        file_name::render(file_name::DEFAULT_TEMPLATE, values, &now, counter)
+            .expect("default template must be valid")
+    });
+
+    file_name::unique_path(&settings.saving_location(), &file_name, extension.as_ref())
+}
+
+/// Maps a peak in dB to the range 0..=1, linearly in dB.
+fn peak_to_level(peak_db: f64) -> f64 {
+    if peak_db.is_nan() {
+        return 0.0;
+    }
+
+    (1.0 - peak_db / MIN_PEAK_DB).clamp(0.0, 1.0)
+}
+
+/// Sets the text of the timestamp overlay from the running time of each frame
+/// before it is drawn on, so that the text matches the frame instead of
+/// lagging behind it. The elapsed time leaves out the pauses, like the
+/// duration.
+fn attach_timestamp_updater(
+    element: &gst::Element,
+    timestamp_overlay: TimestampOverlay,
+    pause_gate: PauseGate,
+) {
+    let weak_element = element.downgrade();
+    let text = Mutex::new(String::new());
+    element.static_pad("video_sink").unwrap().add_probe(
+        gst::PadProbeType::BUFFER,
+        move |pad, info| {
+            let Some(running_time) = info
+                .buffer()
+                .and_then(|buffer| pause_gate::buffer_running_time(pad, buffer))
+            else {
+                return gst::PadProbeReturn::Ok;
+            };
+            let Some(element) = weak_element.upgrade() else {
+                return gst::PadProbeReturn::Ok;
+            };
+
+            // The frame was captured earlier than now by the latency.
+            let latency = element
+                .current_running_time()
+                .map_or(gst::ClockTime::ZERO, |now| now.saturating_sub(running_time));
+            let now = glib::DateTime::now_local().expect("You are somehow on year 9999");
+            let captured_at = now.add_seconds(-latency.seconds_f64()).unwrap_or(now);
+
+            let new_text = timestamp_text(
+                timestamp_overlay,
+                &captured_at,
+                pause_gate.output_time(running_time),
+            );
+            let mut text = text.lock().unwrap();
+            if *text != new_text {
+                element.set_property("text", &new_text);
+                *text = new_text;
+            }
+
+            gst::PadProbeReturn::Ok
+        },
+    );
+}
+
+/// Returns the text of the timestamp overlay, with the wall-clock time
+/// precise to the second.
+fn timestamp_text(
+    timestamp_overlay: TimestampOverlay,
+    now: &glib::DateTime,
+    elapsed: gst::ClockTime,
+) -> String {
+    let wall_clock = || String::from(now.format("%F %T").expect("Invalid format string"));
+
+    match timestamp_overlay {
+        TimestampOverlay::None => String::new(),
+        TimestampOverlay::WallClock => wall_clock(),
+        TimestampOverlay::Elapsed => format::elapsed_time(elapsed),
+        TimestampOverlay::Both => {
+            format!("{}  {}", wall_clock(), format::elapsed_time(elapsed))
+        }
+    }
+}
+
+/// Adds the file as the most recent one, moving it if it was already there,
+/// as splitmuxsink reuses the locations of the deleted files when it has a
+/// max files limit.
+fn push_split_file(split_files: &mut Vec<gio::File>, file: gio::File) {
+    split_files.retain(|split_file| !split_file.equal(&file));
+    split_files.push(file);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_push_split_file() {
+        let file = |name: &str| gio::File::for_path(Path::new("/videos").join(name));
+        let paths = |split_files: &[gio::File]| {
+            split_files
+                .iter()
+                .map(|file| file.path().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut split_files = Vec::new();
+        for name in [
+            "a-001.mkv",
+            "a-002.mkv",
+            "a-001.mkv",
+            "a-002.mkv",
+            "a-001.mkv",
+        ] {
+            push_split_file(&mut split_files, file(name));
+        }
+
+        assert_eq!(
+            paths(&split_files),
+            [
+                PathBuf::from("/videos/a-002.mkv"),
+                PathBuf::from("/videos/a-001.mkv")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peak_to_level() {
+        assert_eq!(peak_to_level(0.0), 1.0);
+        assert_eq!(peak_to_level(3.0), 1.0);
+        assert_eq!(peak_to_level(-30.0), 0.5);
+        assert_eq!(peak_to_level(MIN_PEAK_DB), 0.0);
+        assert_eq!(peak_to_level(f64::NEG_INFINITY), 0.0);
+        assert_eq!(peak_to_level(f64::NAN), 0.0);
+    }
+
+    #[test]
+    fn test_on_pipeline_state_changed() {
+        assert_eq!(
+            RecordingState::Init.on_pipeline_state_changed(gst::State::Paused),
+            None
+        );
+        assert_eq!(
+            RecordingState::Delayed { secs_left: 0 }.on_pipeline_state_changed(gst::State::Playing),
+            Some(RecordingState::Recording)
+        );
 
-    let mut path = saving_location.join(file_name);
-    path.set_extension(extension);
+        // The video encoder fell back while paused.
+        for pipeline_state in [gst::State::Ready, gst::State::Paused, gst::State::Playing] {
+            assert_eq!(
+                RecordingState::Paused.on_pipeline_state_changed(pipeline_state),
+                None
+            );
+            assert_eq!(
+                RecordingState::Recording.on_pipeline_state_changed(pipeline_state),
+                None
+            );
+        }
+    }
 
-    path
+    #[test]
+    fn test_disk_space() {
+        let disk_space = |free, byte_rate| DiskSpace {
+            checked_at: Instant::now(),
+            size: 0,
+            free,
+            byte_rate,
+        };
+
+        assert_eq!(disk_space(DISK_SPACE_RESERVE * 2, None).time_left(), None);
+        assert!(!disk_space(DISK_SPACE_RESERVE * 2, None).is_exhausted());
+        assert!(disk_space(DISK_SPACE_RESERVE - 1, None).is_exhausted());
+
+        let byte_rate = Some(1_000_000.0);
+        assert_eq!(
+            disk_space(DISK_SPACE_RESERVE + 60_000_000, byte_rate).time_left(),
+            Some(gst::ClockTime::from_seconds(60))
+        );
+        assert_eq!(
+            disk_space(DISK_SPACE_RESERVE / 2, byte_rate).time_left(),
+            Some(gst::ClockTime::ZERO)
+        );
+        assert!(!disk_space(DISK_SPACE_RESERVE + 60_000_000, byte_rate).is_exhausted());
+        assert!(disk_space(DISK_SPACE_RESERVE + 3_000_000, byte_rate).is_exhausted());
+    }
+
+    #[test]
+    fn test_timestamp_text() {
+        let now = glib::DateTime::from_utc(2024, 1, 2, 3, 4, 5.5).unwrap();
+        let elapsed = gst::ClockTime::from_seconds(83);
+
+        assert_eq!(timestamp_text(TimestampOverlay::None, &now, elapsed), "");
+        assert_eq!(
+            timestamp_text(TimestampOverlay::WallClock, &now, elapsed),
+            "2024-01-02 03:04:05"
+        );
+        assert_eq!(
+            timestamp_text(TimestampOverlay::Elapsed, &now, elapsed),
+            "00:01:23"
+        );
+        assert_eq!(
+            timestamp_text(TimestampOverlay::Both, &now, elapsed),
+            "2024-01-02 03:04:05  00:01:23"
+        );
+    }
 }
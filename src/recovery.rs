@@ -0,0 +1,336 @@
+use std::{
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{Context, Error, Result, anyhow, bail};
+use futures_util::StreamExt;
+use gst::prelude::*;
+use gtk::{
+    gio::{self, prelude::*},
+    glib::{self, clone},
+};
+
+use crate::{profile::Profile, settings::Settings};
+
+/// Files modified more recently than this are assumed to still be written to,
+/// e.g., by a recording started from the command line.
+const MIN_IDLE_TIME: Duration = Duration::from_secs(30);
+
+const GIF_TRAILER: u8 = 0x3B;
+
+/// Returns the recordings that were never finalized, which happens when the
+/// app crashed or got killed while recording.
+///
+/// Marks for recordings that no longer exist or are empty are dropped.
+pub fn leftover_recordings(settings: &Settings) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+
+    for path in settings.incomplete_recordings() {
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() > 0 => metadata,
+            _ => {
+                tracing::debug!("Dropping mark for missing `{}`", path.display());
+                settings.remove_incomplete_recording(&path);
+                continue;
+            }
+        };
+
+        let is_idle = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|elapsed| elapsed >= MIN_IDLE_TIME);
+
+        if is_idle {
+            ret.push(path);
+        } else {
+            tracing::debug!("Skipping `{}` as it is still being written", path.display());
+        }
+    }
+
+    ret
+}
+
+/// Writes a playable copy of the incomplete recording next to it, then moves
+/// the original to the trash.
+///
+/// GIFs only need to be terminated, while everything else is remuxed into
+/// its original container, or into Matroska, which accepts all the codecs
+/// used by the profiles, if that fails.
+pub async fn recover(path: &Path) -> Result<PathBuf> {
+    let extension = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+
+    let res = if extension == "gif" {
+        let recovered_path = recovered_path(path, "gif");
+        let (src, dest) = (path.to_path_buf(), recovered_path.clone());
+        let res = gio::spawn_blocking(move || repair_gif(&src, &dest))
+            .await
+            .map_err(|_| anyhow!("Repairing GIF panicked"))
+            .and_then(|res| res);
+        discard_on_err(res, &recovered_path).map(|()| recovered_path)
+    } else {
+        remux_with_fallback(path, extension).await
+    };
+    let recovered_path = res.with_context(|| format!("Failed to recover `{}`", path.display()))?;
+
+    if let Err(err) = gio::File::for_path(path)
+        .trash_future(glib::Priority::DEFAULT)
+        .await
+    {
+        tracing::warn!("Failed to trash `{}`: {:?}", path.display(), err);
+    }
+
+    Ok(recovered_path)
+}
+
+/// Remuxes into the container of the extension, falling back to Matroska,
+/// returning the path of the remuxed file.
+async fn remux_with_fallback(path: &Path, extension: &str) -> Result<PathBuf> {
+    match original_muxer(extension) {
+        Some(muxer_factory) => {
+            let recovered_path = recovered_path(path, extension);
+            let res = remux(path, &recovered_path, &muxer_factory).await;
+
+            match discard_on_err(res, &recovered_path) {
+                Ok(()) => return Ok(recovered_path),
+                Err(err) => tracing::warn!(
+                    "Failed to remux with `{}`, falling back to Matroska: {:?}",
+                    muxer_factory.name(),
+                    err
+                ),
+            }
+        }
+        None => tracing::debug!("No muxer for `{}`, falling back to Matroska", extension),
+    }
+
+    let muxer_factory =
+        gst::ElementFactory::find("matroskamux").context("Matroska muxer is not installed")?;
+    let recovered_path = recovered_path(path, "mkv");
+    let res = remux(path, &recovered_path, &muxer_factory).await;
+    discard_on_err(res, &recovered_path)?;
+
+    Ok(recovered_path)
+}
+
+/// Returns the muxer of the profiles that record into files with the
+/// extension.
+fn original_muxer(extension: &str) -> Option<gst::ElementFactory> {
+    let profiles = Profile::all()
+        .inspect_err(|err| tracing::warn!("Failed to load profiles: {:?}", err))
+        .unwrap_or_default();
+
+    profiles
+        .iter()
+        .filter(|profile| profile.file_extension() == extension)
+        .find_map(|profile| profile.muxer_factory().ok())
+        .or_else(|| match extension {
+            // In case the profile that recorded it is gone.
+            "mp4" => gst::ElementFactory::find("mp4mux"),
+            _ => None,
+        })
+}
+
+/// Removes what was written of the recovered file if recovering failed.
+fn discard_on_err(res: Result<()>, recovered_path: &Path) -> Result<()> {
+    if res.is_err()
+        && let Err(err) = fs::remove_file(recovered_path)
+    {
+        tracing::debug!("Failed to remove partially recovered file: {:?}", err);
+    }
+
+    res
+}
+
+/// Returns a path beside the given one that does not exist yet.
+fn recovered_path(path: &Path, extension: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    (1..)
+        .map(|n| {
+            let file_name = if n == 1 {
+                format!("{}-recovered.{}", stem, extension)
+            } else {
+                format!("{}-recovered-{}.{}", stem, n, extension)
+            };
+            path.with_file_name(file_name)
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Copies the GIF and terminates it, as the trailer is only written on EOS.
+fn repair_gif(src: &Path, dest: &Path) -> Result<()> {
+    fs::copy(src, dest).context("Failed to copy GIF")?;
+
+    let mut file = OpenOptions::new().read(true).append(true).open(dest)?;
+
+    let mut last_byte = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last_byte)?;
+
+    if last_byte[0] != GIF_TRAILER {
+        file.write_all(&[GIF_TRAILER])?;
+    }
+
+    Ok(())
+}
+
+/// Muxes the streams of the source into a new file with a fresh muxer from
+/// the factory, as the one of the profile may be set up for crash tolerance.
+async fn remux(src: &Path, dest: &Path, muxer_factory: &gst::ElementFactory) -> Result<()> {
+    let pipeline = gst::Pipeline::new();
+
+    let filesrc = gst::ElementFactory::make("filesrc")
+        .property(
+            "location",
+            src.to_str()
+                .context("Could not convert src path to string")?,
+        )
+        .build()?;
+    let parsebin = gst::ElementFactory::make("parsebin").build()?;
+    let muxer = muxer_factory.create().build()?;
+    let filesink = gst::ElementFactory::make("filesink")
+        .property(
+            "location",
+            dest.to_str()
+                .context("Could not convert dest path to string")?,
+        )
+        .build()?;
+
+    pipeline.add_many([&filesrc, &parsebin, &muxer, &filesink])?;
+    filesrc.link(&parsebin)?;
+    muxer.link(&filesink)?;
+
+    parsebin.connect_pad_added(clone!(
+        #[weak]
+        muxer,
+        move |_, pad| {
+            if let Err(err) = link_to_muxer(pad, &muxer) {
+                tracing::warn!("Skipping stream: {:?}", err);
+            }
+        }
+    ));
+
+    pipeline
+        .set_state(gst::State::Playing)
+        .context("Failed to set remux pipeline state to playing")?;
+
+    let res = wait_for_eos(&pipeline, &muxer, &filesink).await;
+
+    if let Err(err) = pipeline.set_state(gst::State::Null) {
+        tracing::warn!("Failed to stop remux pipeline: {:?}", err);
+    }
+
+    res
+}
+
+fn link_to_muxer(pad: &gst::Pad, muxer: &gst::Element) -> Result<()> {
+    let caps = pad.current_caps().context("Pad has no caps")?;
+    let media_type = caps
+        .structure(0)
+        .context("Caps has no structure")?
+        .name()
+        .to_string();
+
+    let template_name = if media_type.starts_with("video/") {
+        "video_%u"
+    } else if media_type.starts_with("audio/") {
+        "audio_%u"
+    } else {
+        bail!("Unsupported media type `{}`", media_type);
+    };
+
+    let muxer_pad = muxer
+        .request_pad_simple(template_name)
+        .with_context(|| format!("Failed to request `{}` pad", template_name))?;
+    pad.link(&muxer_pad)?;
+
+    Ok(())
+}
+
+async fn wait_for_eos(
+    pipeline: &gst::Pipeline,
+    muxer: &gst::Element,
+    filesink: &gst::Element,
+) -> Result<()> {
+    use gst::MessageView;
+
+    let bus = pipeline.bus().unwrap();
+    let mut messages = bus.stream_filtered(&[gst::MessageType::Eos, gst::MessageType::Error]);
+
+    let mut is_finalizing = false;
+    while let Some(message) = messages.next().await {
+        match message.view() {
+            MessageView::Eos(..) => return Ok(()),
+            MessageView::Error(e) => {
+                let is_from_output = message.src().is_some_and(|src| {
+                    src == muxer.upcast_ref::<gst::Object>()
+                        || src == filesink.upcast_ref::<gst::Object>()
+                });
+
+                if is_from_output || is_finalizing {
+                    return Err(Error::from(e.error())
+                        .context(e.debug().unwrap_or_else(|| "<no debug>".into())));
+                }
+
+                // Truncated files are expected to error out on the demuxer, so
+                // keep whatever was demuxed until then.
+                tracing::debug!("Finalizing after input error: {:?}", e.error());
+                is_finalizing = true;
+
+                for pad in muxer.sink_pads() {
+                    pad.send_event(gst::event::Eos::new());
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    bail!("Bus stream ended before receiving eos")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovered_path_is_unique() {
+        let dir = glib::mkdtemp("kooha-test-XXXXXX").unwrap();
+        let path = dir.join("Kooha-2024-01-01-00-00-00.webm");
+
+        assert_eq!(
+            recovered_path(&path, "mkv"),
+            dir.join("Kooha-2024-01-01-00-00-00-recovered.mkv")
+        );
+
+        fs::write(dir.join("Kooha-2024-01-01-00-00-00-recovered.mkv"), b"").unwrap();
+        assert_eq!(
+            recovered_path(&path, "mkv"),
+            dir.join("Kooha-2024-01-01-00-00-00-recovered-2.mkv")
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn repair_gif_appends_trailer() {
+        let dir = glib::mkdtemp("kooha-test-XXXXXX").unwrap();
+        let src = dir.join("src.gif");
+        let dest = dir.join("dest.gif");
+
+        fs::write(&src, b"GIF89a\x00").unwrap();
+        repair_gif(&src, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"GIF89a\x00\x3B");
+
+        // Already terminated GIFs are left as is.
+        fs::write(&src, b"GIF89a\x3B").unwrap();
+        repair_gif(&src, &dest).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"GIF89a\x3B");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
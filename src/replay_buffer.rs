@@ -0,0 +1,422 @@
+use std::{
+    collections::VecDeque,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Error, Result, bail};
+use futures_util::StreamExt;
+use gst::prelude::*;
+
+use crate::profile::Profile;
+
+/// Encoders may only emit a keyframe at the start, so one is requested at
+/// this interval to have points where the buffer can be trimmed.
+const KEYFRAME_INTERVAL: gst::ClockTime = gst::ClockTime::from_seconds(2);
+
+/// Keeps the most recent encoded streams of a recording in memory, so that
+/// they can be written to a file without stopping the capture.
+#[derive(Debug, Clone)]
+pub struct ReplayBuffer {
+    profile: Profile,
+    ring: Arc<Mutex<Ring>>,
+}
+
+impl ReplayBuffer {
+    pub fn new(profile: Profile, max_duration: gst::ClockTime) -> Self {
+        Self {
+            profile,
+            ring: Arc::new(Mutex::new(Ring::new(max_duration, 0))),
+        }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    /// Starts collecting the buffers that pass through the encoder src pads,
+    /// with a separate track for each audio pad.
+    pub fn attach(&self, videoenc_pad: &gst::Pad, audioenc_pads: &[gst::Pad]) {
+        self.ring.lock().unwrap().audio = vec![Track::default(); audioenc_pads.len()];
+
+        add_probe(videoenc_pad, &self.ring, TrackKind::Video);
+
+        for (index, audioenc_pad) in audioenc_pads.iter().enumerate() {
+            add_probe(audioenc_pad, &self.ring, TrackKind::Audio(index));
+        }
+    }
+
+    /// Writes what is currently in the buffer to `path`, returning the
+    /// duration of what was written.
+    pub async fn save(&self, path: &Path) -> Result<gst::ClockTime> {
+        let (video, audio_tracks) = self
+            .ring
+            .lock()
+            .unwrap()
+            .snapshot()
+            .context("Nothing has been recorded yet")?;
+
+        let pipeline = gst::Pipeline::new();
+
+        let video_src = make_appsrc(&video)?;
+        let audio_srcs = audio_tracks
+            .iter()
+            .map(make_appsrc)
+            .collect::<Result<Vec<_>>>()?;
+        let filesink = gst::ElementFactory::make("filesink")
+            .property(
+                "location",
+                path.to_str()
+                    .context("Could not convert file path to string")?,
+            )
+            .build()?;
+
+        pipeline.add_many([&video_src, &filesink])?;
+        pipeline.add_many(&audio_srcs)?;
+
+        self.profile
+            .attach_muxer(&pipeline, &video_src, &audio_srcs, &filesink)
+            .with_context(|| {
+                format!(
+                    "Failed to attach profile `{}` to replay pipeline",
+                    self.profile.id()
+                )
+            })?;
+
+        pipeline
+            .set_state(gst::State::Playing)
+            .context("Failed to set replay pipeline state to playing")?;
+
+        let base = video.start_time();
+        push_track(&video_src, &video, base)?;
+        for (audio_src, audio) in audio_srcs.iter().zip(&audio_tracks) {
+            push_track(audio_src, audio, base)?;
+        }
+
+        let res = wait_for_eos(&pipeline).await;
+
+        if let Err(err) = pipeline.set_state(gst::State::Null) {
+            tracing::warn!("Failed to stop replay pipeline: {:?}", err);
+        }
+
+        res?;
+
+        Ok(video.end_time().saturating_sub(base))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TrackKind {
+    Video,
+    Audio(usize),
+}
+
+#[derive(Debug, Default, Clone)]
+struct Track {
+    caps: Option<gst::Caps>,
+    buffers: VecDeque<gst::Buffer>,
+}
+
+impl Track {
+    fn start_time(&self) -> gst::ClockTime {
+        self.buffers
+            .front()
+            .and_then(|buffer| buffer.dts_or_pts())
+            .unwrap_or(gst::ClockTime::ZERO)
+    }
+
+    fn end_time(&self) -> gst::ClockTime {
+        self.buffers
+            .back()
+            .and_then(|buffer| {
+                let duration = buffer.duration().unwrap_or(gst::ClockTime::ZERO);
+                buffer.pts().map(|pts| pts + duration)
+            })
+            .unwrap_or(gst::ClockTime::ZERO)
+    }
+}
+
+#[derive(Debug)]
+struct Ring {
+    max_duration: gst::ClockTime,
+    video: Track,
+    audio: Vec<Track>,
+    last_keyframe_request: Option<gst::ClockTime>,
+}
+
+impl Ring {
+    fn new(max_duration: gst::ClockTime, n_audio_tracks: usize) -> Self {
+        Self {
+            max_duration,
+            video: Track::default(),
+            audio: vec![Track::default(); n_audio_tracks],
+            last_keyframe_request: None,
+        }
+    }
+
+    fn track_mut(&mut self, kind: TrackKind) -> &mut Track {
+        match kind {
+            TrackKind::Video => &mut self.video,
+            TrackKind::Audio(index) => &mut self.audio[index],
+        }
+    }
+
+    /// Returns `true` if a keyframe should be requested upstream.
+    fn push(&mut self, kind: TrackKind, buffer: gst::Buffer) -> bool {
+        let Some(pts) = buffer.pts() else {
+            tracing::trace!("Dropping buffer without pts");
+            return false;
+        };
+
+        self.track_mut(kind).buffers.push_back(buffer);
+
+        match kind {
+            TrackKind::Video => {
+                self.trim(pts);
+
+                let needs_keyframe = self
+                    .last_keyframe_request
+                    .is_none_or(|last| pts.saturating_sub(last) >= KEYFRAME_INTERVAL);
+                if needs_keyframe {
+                    self.last_keyframe_request = Some(pts);
+                }
+                needs_keyframe
+            }
+            TrackKind::Audio(_) => false,
+        }
+    }
+
+    /// Drops everything before the last keyframe that is at least
+    /// `max_duration` older than `now`.
+    fn trim(&mut self, now: gst::ClockTime) {
+        let Some(cut) = now.checked_sub(self.max_duration) else {
+            return;
+        };
+
+        let n_droppable = self
+            .video
+            .buffers
+            .iter()
+            .rposition(|buffer| is_keyframe(buffer) && buffer.pts().is_some_and(|pts| pts <= cut))
+            .unwrap_or(0);
+        self.video.buffers.drain(..n_droppable);
+
+        // Don't grow indefinitely if the encoder ignores keyframe requests.
+        if self.video.start_time() + self.max_duration * 2 < now {
+            tracing::warn!("No keyframe received in time, dropping old buffers");
+
+            while self.video.buffers.front().is_some_and(|buffer| {
+                !is_keyframe(buffer) || buffer.pts().is_some_and(|pts| pts <= cut)
+            }) {
+                self.video.buffers.pop_front();
+            }
+        }
+
+        let video_start = self.video.start_time();
+        for audio in &mut self.audio {
+            while audio
+                .buffers
+                .front()
+                .and_then(|buffer| buffer.pts())
+                .is_some_and(|pts| pts < video_start)
+            {
+                audio.buffers.pop_front();
+            }
+        }
+    }
+
+    /// Returns copies of the video and audio tracks, or `None` if there is
+    /// no keyframe to start from yet.
+    ///
+    /// Audio tracks that have not received caps are left out.
+    fn snapshot(&self) -> Option<(Track, Vec<Track>)> {
+        if !self.video.buffers.front().is_some_and(is_keyframe) || self.video.caps.is_none() {
+            return None;
+        }
+
+        let audio_tracks = self
+            .audio
+            .iter()
+            .filter(|audio| audio.caps.is_some())
+            .cloned()
+            .collect();
+
+        Some((self.video.clone(), audio_tracks))
+    }
+}
+
+fn add_probe(pad: &gst::Pad, ring: &Arc<Mutex<Ring>>, kind: TrackKind) {
+    let ring = Arc::clone(ring);
+    pad.add_probe(
+        gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+        move |pad, info| {
+            if let Some(buffer) = info.buffer() {
+                let needs_keyframe = ring.lock().unwrap().push(kind, buffer.clone());
+
+                if needs_keyframe {
+                    let event = gst::event::CustomUpstream::new(
+                        gst::Structure::builder("GstForceKeyUnit")
+                            .field("all-headers", true)
+                            .build(),
+                    );
+                    if !pad.send_event(event) {
+                        tracing::trace!("Keyframe request was not handled");
+                    }
+                }
+            } else if let Some(event) = info.event()
+                && let gst::EventView::Caps(caps) = event.view()
+            {
+                ring.lock().unwrap().track_mut(kind).caps = Some(caps.caps_owned());
+            }
+
+            gst::PadProbeReturn::Ok
+        },
+    );
+}
+
+fn make_appsrc(track: &Track) -> Result<gst::Element> {
+    let appsrc = gst::ElementFactory::make("appsrc")
+        .property("caps", track.caps.as_ref().context("Track has no caps")?)
+        .property_from_str("format", "time")
+        // Everything is pushed at once, so it must not be dropped.
+        .property("max-bytes", 0_u64)
+        .build()?;
+
+    Ok(appsrc)
+}
+
+/// Pushes the buffers of the track with timestamps relative to `base`.
+fn push_track(appsrc: &gst::Element, track: &Track, base: gst::ClockTime) -> Result<()> {
+    for buffer in &track.buffers {
+        let mut buffer = buffer.clone();
+        {
+            let buffer = buffer.make_mut();
+            buffer.set_pts(buffer.pts().map(|pts| pts.saturating_sub(base)));
+            buffer.set_dts(buffer.dts().map(|dts| dts.saturating_sub(base)));
+        }
+
+        let ret = appsrc.emit_by_name::<gst::FlowReturn>("push-buffer", &[&buffer]);
+        if let Err(err) = ret.into_result() {
+            bail!("Failed to push buffer: {:?}", err);
+        }
+    }
+
+    let ret = appsrc.emit_by_name::<gst::FlowReturn>("end-of-stream", &[]);
+    if let Err(err) = ret.into_result() {
+        bail!("Failed to end stream: {:?}", err);
+    }
+
+    Ok(())
+}
+
+async fn wait_for_eos(pipeline: &gst::Pipeline) -> Result<()> {
+    use gst::MessageView;
+
+    let bus = pipeline.bus().unwrap();
+    let mut messages = bus.stream_filtered(&[gst::MessageType::Eos, gst::MessageType::Error]);
+
+    let message = messages
+        .next()
+        .await
+        .context("Bus stream ended before receiving eos")?;
+
+    match message.view() {
+        MessageView::Eos(..) => Ok(()),
+        MessageView::Error(e) => {
+            Err(Error::from(e.error()).context(e.debug().unwrap_or_else(|| "<no debug>".into())))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn is_keyframe(buffer: &gst::Buffer) -> bool {
+    !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(pts_secs: u64, is_keyframe: bool) -> gst::Buffer {
+        let mut buffer = gst::Buffer::new();
+        {
+            let buffer = buffer.get_mut().unwrap();
+            buffer.set_pts(gst::ClockTime::from_seconds(pts_secs));
+            if !is_keyframe {
+                buffer.set_flags(gst::BufferFlags::DELTA_UNIT);
+            }
+        }
+        buffer
+    }
+
+    fn video_pts(ring: &Ring) -> Vec<u64> {
+        ring.video
+            .buffers
+            .iter()
+            .map(|buffer| buffer.pts().unwrap().seconds())
+            .collect()
+    }
+
+    #[test]
+    fn trim_keeps_keyframe() {
+        gst::init().unwrap();
+
+        let mut ring = Ring::new(gst::ClockTime::from_seconds(3), 0);
+        for secs in 0..=6 {
+            ring.push(TrackKind::Video, buffer(secs, secs % 2 == 0));
+        }
+
+        // 3 is the cut, but it is a delta unit, so the buffer starts at the
+        // keyframe before it.
+        assert_eq!(video_pts(&ring), [2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn trim_audio_before_video() {
+        gst::init().unwrap();
+
+        let mut ring = Ring::new(gst::ClockTime::from_seconds(2), 2);
+        for secs in 0..=4 {
+            ring.push(TrackKind::Audio(0), buffer(secs, true));
+            ring.push(TrackKind::Audio(1), buffer(secs, true));
+            ring.push(TrackKind::Video, buffer(secs, true));
+        }
+
+        assert_eq!(video_pts(&ring), [2, 3, 4]);
+        for audio in &ring.audio {
+            assert_eq!(
+                audio
+                    .buffers
+                    .iter()
+                    .map(|buffer| buffer.pts().unwrap().seconds())
+                    .collect::<Vec<_>>(),
+                [2, 3, 4]
+            );
+        }
+    }
+
+    #[test]
+    fn keyframe_requests() {
+        gst::init().unwrap();
+
+        let mut ring = Ring::new(gst::ClockTime::from_seconds(30), 1);
+        assert!(ring.push(TrackKind::Video, buffer(0, true)));
+        assert!(!ring.push(TrackKind::Video, buffer(1, false)));
+        assert!(ring.push(TrackKind::Video, buffer(2, false)));
+        assert!(!ring.push(TrackKind::Audio(0), buffer(5, true)));
+    }
+
+    #[test]
+    fn snapshot_requires_keyframe_and_caps() {
+        gst::init().unwrap();
+
+        let mut ring = Ring::new(gst::ClockTime::from_seconds(30), 1);
+        ring.push(TrackKind::Video, buffer(0, true));
+        assert!(ring.snapshot().is_none());
+
+        ring.video.caps = Some(gst::Caps::new_empty_simple("video/x-vp8"));
+        let (video, audio_tracks) = ring.snapshot().unwrap();
+        assert_eq!(video.buffers.len(), 1);
+        assert!(audio_tracks.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+use anyhow::{Context, Result, ensure};
+use gtk::glib;
+
+/// A recording that starts, and optionally stops, at a wall-clock time.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub start: glib::DateTime,
+    pub stop: Option<glib::DateTime>,
+}
+
+impl Schedule {
+    /// Parses the start and stop times with `parse_time`, where the stop is
+    /// the next occurrence after the start.
+    pub fn parse(start: &str, stop: Option<&str>, now: &glib::DateTime) -> Result<Self> {
+        let start = parse_time(start, now).context("Invalid start time")?;
+        let stop = stop
+            .map(|stop| parse_time(stop, &start).context("Invalid stop time"))
+            .transpose()?;
+
+        Ok(Self { start, stop })
+    }
+}
+
+/// Parses a time as `HH:MM`, for its next occurrence after `after`, or as
+/// `YYYY-MM-DD HH:MM`, which must be after `after`.
+fn parse_time(raw: &str, after: &glib::DateTime) -> Result<glib::DateTime> {
+    let raw = raw.trim();
+
+    let (date, time) = match raw.split_once(' ') {
+        Some((date, time)) => (Some(date), time.trim()),
+        None => (None, raw),
+    };
+
+    let (hour, minute) = time
+        .split_once(':')
+        .with_context(|| format!("Time `{}` is not in HH:MM", time))?;
+    let hour = hour
+        .parse::<i32>()
+        .with_context(|| format!("Invalid hour `{}`", hour))?;
+    let minute = minute
+        .parse::<i32>()
+        .with_context(|| format!("Invalid minute `{}`", minute))?;
+    ensure!((0..24).contains(&hour), "Hour `{}` is out of range", hour);
+    ensure!(
+        (0..60).contains(&minute),
+        "Minute `{}` is out of range",
+        minute
+    );
+
+    let timezone = after.timezone();
+
+    let Some(date) = date else {
+        let time = glib::DateTime::new(
+            &timezone,
+            after.year(),
+            after.month(),
+            after.day_of_month(),
+            hour,
+            minute,
+            0.0,
+        )?;
+        return if time > *after {
+            Ok(time)
+        } else {
+            Ok(time.add_days(1)?)
+        };
+    };
+
+    let [year, month, day] = date
+        .splitn(3, '-')
+        .map(|part| part.parse::<i32>().ok())
+        .collect::<Option<Vec<_>>>()
+        .and_then(|parts| <[i32; 3]>::try_from(parts).ok())
+        .with_context(|| format!("Date `{}` is not in YYYY-MM-DD", date))?;
+    let time = glib::DateTime::new(&timezone, year, month, day, hour, minute, 0.0)
+        .with_context(|| format!("Invalid date `{}`", date))?;
+    ensure!(time > *after, "Time `{}` has already passed", raw);
+
+    Ok(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_time(day: i32, hour: i32, minute: i32) -> glib::DateTime {
+        glib::DateTime::new(&glib::TimeZone::utc(), 2024, 1, day, hour, minute, 0.0).unwrap()
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let now = date_time(2, 12, 30);
+
+        assert_eq!(parse_time("13:00", &now).unwrap(), date_time(2, 13, 0));
+        assert_eq!(parse_time(" 9:05 ", &now).unwrap(), date_time(3, 9, 5));
+        assert_eq!(parse_time("12:30", &now).unwrap(), date_time(3, 12, 30));
+        assert_eq!(
+            parse_time("2024-01-05 08:00", &now).unwrap(),
+            date_time(5, 8, 0)
+        );
+
+        assert!(parse_time("2024-01-01 08:00", &now).is_err());
+        assert!(parse_time("2024-01-32 08:00", &now).is_err());
+        assert!(parse_time("2024-01 08:00", &now).is_err());
+        assert!(parse_time("24:00", &now).is_err());
+        assert!(parse_time("12:60", &now).is_err());
+        assert!(parse_time("noon", &now).is_err());
+        assert!(parse_time("", &now).is_err());
+    }
+
+    #[test]
+    fn test_schedule_parse() {
+        let now = date_time(2, 12, 30);
+
+        let schedule = Schedule::parse("23:00", Some("01:00"), &now).unwrap();
+        assert_eq!(schedule.start, date_time(2, 23, 0));
+        assert_eq!(schedule.stop, Some(date_time(3, 1, 0)));
+
+        let schedule = Schedule::parse("13:00", None, &now).unwrap();
+        assert_eq!(schedule.stop, None);
+
+        assert!(Schedule::parse("13:00", Some("2024-01-02 12:45"), &now).is_err());
+    }
+}
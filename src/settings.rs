@@ -1,4 +1,8 @@
-use std::{fs, path::PathBuf, time::Duration};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use adw::prelude::*;
 use anyhow::{Context, Result};
@@ -9,7 +13,8 @@ use gtk::{gio, glib};
 use crate::{
     area_selector::{Selection, SelectionContext},
     config::APP_ID,
-    profile::Profile,
+    pipeline::{Limits, SplitLimits},
+    profile::{Profile, Quality},
 };
 
 #[gen_settings(file = "./data/io.github.seadve.Kooha.gschema.xml.in")]
@@ -20,9 +25,11 @@ use crate::{
     ret_type = "SelectionContext"
 )]
 #[gen_settings_skip(key_name = "saving-location")]
+#[gen_settings_skip(key_name = "watermark")]
 #[gen_settings_skip(key_name = "framerate")]
 #[gen_settings_skip(key_name = "record-delay")]
 #[gen_settings_skip(key_name = "profile-id")]
+#[gen_settings_skip(key_name = "incomplete-recordings")]
 pub struct Settings;
 
 impl Default for Settings {
@@ -46,11 +53,15 @@ impl Settings {
 
         let folder = dialog.select_folder_future(parent).await?;
         let path = folder.path().context("Folder does not have a path")?;
-        self.0.set("saving-location", path).unwrap();
+        self.set_saving_location(&path);
 
         Ok(())
     }
 
+    pub fn set_saving_location(&self, path: &Path) {
+        self.0.set("saving-location", path).unwrap();
+    }
+
     pub fn saving_location(&self) -> PathBuf {
         let stored_saving_location: PathBuf = self.0.get("saving-location");
 
@@ -85,6 +96,48 @@ impl Settings {
             })
     }
 
+    /// Opens a `FileDialog` to select an image and updates
+    /// the settings with the selected image as the watermark.
+    pub async fn select_watermark(&self, parent: Option<&impl IsA<gtk::Window>>) -> Result<()> {
+        let filter = gtk::FileFilter::new();
+        filter.set_name(Some(&gettext("Images")));
+        filter.add_mime_type("image/*");
+
+        let filters = gio::ListStore::new::<gtk::FileFilter>();
+        filters.append(&filter);
+
+        let dialog = gtk::FileDialog::builder()
+            .modal(true)
+            .title(gettext("Select Watermark"))
+            .filters(&filters)
+            .build();
+
+        let file = dialog.open_future(parent).await?;
+        let path = file.path().context("File does not have a path")?;
+        self.set_watermark(Some(&path));
+
+        Ok(())
+    }
+
+    pub fn set_watermark(&self, path: Option<&Path>) {
+        self.0
+            .set("watermark", path.unwrap_or(Path::new("")))
+            .unwrap();
+    }
+
+    /// Returns the image to burn in the recordings, if any.
+    pub fn watermark(&self) -> Option<PathBuf> {
+        let path: PathBuf = self.0.get("watermark");
+        (!path.as_os_str().is_empty()).then_some(path)
+    }
+
+    pub fn connect_watermark_changed(&self, f: impl Fn(&Self) + 'static) -> glib::SignalHandlerId {
+        self.0
+            .connect_changed(Some("watermark"), move |settings, _| {
+                f(&Self(settings.clone()));
+            })
+    }
+
     pub fn framerate(&self) -> gst::Fraction {
         self.0.get::<(i32, i32)>("framerate").into()
     }
@@ -105,6 +158,12 @@ impl Settings {
         Duration::from_secs(self.0.get::<u32>("record-delay") as u64)
     }
 
+    pub fn set_record_delay(&self, record_delay: Duration) {
+        self.0
+            .set("record-delay", record_delay.as_secs() as u32)
+            .unwrap();
+    }
+
     pub fn create_record_delay_action(&self) -> gio::Action {
         self.0.create_action("record-delay")
     }
@@ -117,13 +176,68 @@ impl Settings {
         self.0.bind("record-delay", object, property)
     }
 
+    pub fn quality(&self) -> Quality {
+        match self.video_quality() {
+            VideoQuality::Low => Quality::Low,
+            VideoQuality::Medium => Quality::Medium,
+            VideoQuality::High => Quality::High,
+            VideoQuality::Lossless => Quality::Lossless,
+            VideoQuality::Bitrate => Quality::Bitrate(self.video_bitrate()),
+        }
+    }
+
+    pub fn split_limits(&self) -> SplitLimits {
+        SplitLimits {
+            per_file: Limits {
+                max_duration: minutes_setting(self.split_duration()),
+                max_size: megabytes_setting(self.split_size()),
+            },
+            max_files: Some(self.split_max_files()).filter(|n_files| *n_files > 0),
+        }
+    }
+
+    /// Returns the number for the `{counter}` token of the file name
+    /// template, and advances the counter for the next one.
+    pub fn next_file_name_counter(&self) -> u32 {
+        let counter = self.file_name_counter();
+        self.set_file_name_counter(counter.wrapping_add(1).max(1));
+        counter
+    }
+
+    pub fn stop_limits(&self) -> Limits {
+        Limits {
+            max_duration: minutes_setting(self.stop_duration()),
+            max_size: megabytes_setting(self.stop_size()),
+        }
+    }
+
+    /// Paths of the recordings that were started but not yet finalized.
+    pub fn incomplete_recordings(&self) -> Vec<PathBuf> {
+        self.0.get("incomplete-recordings")
+    }
+
+    pub fn add_incomplete_recording(&self, path: &Path) {
+        let mut paths = self.incomplete_recordings();
+
+        if !paths.iter().any(|p| p == path) {
+            paths.push(path.to_path_buf());
+            self.0.set("incomplete-recordings", paths).unwrap();
+        }
+    }
+
+    pub fn remove_incomplete_recording(&self, path: &Path) {
+        let mut paths = self.incomplete_recordings();
+        paths.retain(|p| p != path);
+        self.0.set("incomplete-recordings", paths).unwrap();
+    }
+
     pub fn set_profile(&self, profile: Option<&Profile>) {
         self.0
             .set_string("profile-id", profile.map_or("", |profile| profile.id()))
             .unwrap();
     }
 
-    pub fn profile(&self) -> Option<&'static Profile> {
+    pub fn profile(&self) -> Option<Profile> {
         let profile_id = self.0.get::<String>("profile-id");
 
         if profile_id.is_empty() {
@@ -150,6 +264,16 @@ impl Settings {
     }
 }
 
+/// Converts a setting in minutes to a duration, where 0 means none.
+fn minutes_setting(minutes: u32) -> Option<gst::ClockTime> {
+    (minutes > 0).then(|| gst::ClockTime::from_seconds(minutes as u64 * 60))
+}
+
+/// Converts a setting in megabytes to bytes, where 0 means none.
+fn megabytes_setting(megabytes: u32) -> Option<u64> {
+    (megabytes > 0).then(|| megabytes as u64 * 1_000_000)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -190,4 +314,26 @@ mod tests {
         assert!(Settings::default().profile().is_some());
         assert!(Settings::default().profile().unwrap().supports_audio());
     }
+
+    #[test]
+    fn incomplete_recordings() {
+        setup_schema();
+
+        let settings = Settings::default();
+        assert!(settings.incomplete_recordings().is_empty());
+
+        settings.add_incomplete_recording(Path::new("/tmp/a.webm"));
+        settings.add_incomplete_recording(Path::new("/tmp/b.webm"));
+        settings.add_incomplete_recording(Path::new("/tmp/a.webm"));
+        assert_eq!(
+            settings.incomplete_recordings(),
+            [PathBuf::from("/tmp/a.webm"), PathBuf::from("/tmp/b.webm")]
+        );
+
+        settings.remove_incomplete_recording(Path::new("/tmp/a.webm"));
+        assert_eq!(
+            settings.incomplete_recordings(),
+            [PathBuf::from("/tmp/b.webm")]
+        );
+    }
 }
@@ -27,6 +27,7 @@ impl fmt::Debug for Timer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Timer")
             .field("duration", &self.inner.duration)
+            .field("end", &self.inner.end)
             .field("state", &self.inner.state.get())
             .field("elapsed", &self.inner.instant.get().map(|i| i.elapsed()))
             .finish()
@@ -52,6 +53,8 @@ impl State {
 
 struct Inner {
     duration: Duration,
+    /// Wall-clock time to wait for instead of the duration
+    end: Option<glib::DateTime>,
 
     secs_left_changed_cb: Box<dyn Fn(u64) + 'static>,
     secs_left_changed_source_id: RefCell<Option<glib::SourceId>>,
@@ -69,6 +72,10 @@ impl Inner {
             return 0;
         }
 
+        if let Some(ref end) = self.end {
+            return duration_until(end).as_secs();
+        }
+
         let elapsed_secs = self
             .instant
             .get()
@@ -81,14 +88,44 @@ impl Inner {
     fn is_terminated(&self) -> bool {
         matches!(self.state.get(), State::Done | State::Cancelled)
     }
+
+    fn finish(&self) {
+        self.state.set(State::Done);
+
+        if let Some(source_id) = self.secs_left_changed_source_id.take() {
+            source_id.remove();
+        }
+
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
 }
 
 impl Timer {
     /// The timer will start as soon as it gets polled
     pub fn new(duration: Duration, secs_left_changed_cb: impl Fn(u64) + 'static) -> Self {
+        Self::new_inner(duration, None, secs_left_changed_cb)
+    }
+
+    /// The timer will start as soon as it gets polled, and end at the given
+    /// wall-clock time
+    ///
+    /// Unlike with a duration, the end is not delayed when the system is
+    /// suspended while waiting.
+    pub fn until(end: glib::DateTime, secs_left_changed_cb: impl Fn(u64) + 'static) -> Self {
+        Self::new_inner(duration_until(&end), Some(end), secs_left_changed_cb)
+    }
+
+    fn new_inner(
+        duration: Duration,
+        end: Option<glib::DateTime>,
+        secs_left_changed_cb: impl Fn(u64) + 'static,
+    ) -> Self {
         Self {
             inner: Rc::new(Inner {
                 duration,
+                end,
                 secs_left_changed_cb: Box::new(secs_left_changed_cb),
                 secs_left_changed_source_id: RefCell::new(None),
                 state: Cell::new(State::Waiting),
@@ -99,6 +136,11 @@ impl Timer {
         }
     }
 
+    /// Returns whether both are clones of the same timer.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.inner, &other.inner)
+    }
+
     pub fn cancel(&self) {
         if self.inner.is_terminated() {
             return;
@@ -146,32 +188,37 @@ impl Future for Timer {
                     self.inner,
                     #[upgrade_or_panic]
                     move || {
+                        if inner
+                            .end
+                            .as_ref()
+                            .is_some_and(|end| duration_until(end).is_zero())
+                        {
+                            // Removed by returning `Break` instead.
+                            let _ = inner.secs_left_changed_source_id.take();
+                            inner.finish();
+                            return glib::ControlFlow::Break;
+                        }
+
                         (inner.secs_left_changed_cb)(inner.secs_left());
                         glib::ControlFlow::Continue
                     }
                 ),
             )));
 
-        self.inner
-            .source_id
-            .replace(Some(glib::timeout_add_local_once(
-                self.inner.duration,
-                clone!(
-                    #[weak(rename_to = inner)]
-                    self.inner,
-                    move || {
-                        inner.state.set(State::Done);
-
-                        if let Some(source_id) = inner.secs_left_changed_source_id.take() {
-                            source_id.remove();
-                        }
-
-                        if let Some(waker) = inner.waker.take() {
-                            waker.wake();
+        if self.inner.end.is_none() {
+            self.inner
+                .source_id
+                .replace(Some(glib::timeout_add_local_once(
+                    self.inner.duration,
+                    clone!(
+                        #[weak(rename_to = inner)]
+                        self.inner,
+                        move || {
+                            inner.finish();
                         }
-                    }
-                ),
-            )));
+                    ),
+                )));
+        }
         self.inner.instant.set(Some(Instant::now()));
         (self.inner.secs_left_changed_cb)(self.inner.secs_left());
 
@@ -185,6 +232,13 @@ impl FusedFuture for Timer {
     }
 }
 
+/// Returns how long it is until the wall-clock time, which is zero if it has
+/// already passed.
+fn duration_until(end: &glib::DateTime) -> Duration {
+    let now = glib::DateTime::now_local().expect("You are somehow on year 9999");
+    Duration::from_micros(end.difference(&now).as_microseconds().max(0) as u64)
+}
+
 impl Drop for Timer {
     fn drop(&mut self) {
         self.cancel();
@@ -220,6 +274,23 @@ mod tests {
         assert_eq!(timer.inner.secs_left(), 0);
     }
 
+    #[gtk::test]
+    async fn until() {
+        let end = glib::DateTime::now_local()
+            .unwrap()
+            .add_seconds(0.3)
+            .unwrap();
+        let timer = Timer::until(end.clone(), |_| {});
+        assert!(matches!(timer.inner.state.get(), State::Waiting));
+
+        assert!(timer.clone().await.is_ok());
+        assert!(matches!(timer.inner.state.get(), State::Done));
+        assert!(glib::DateTime::now_local().unwrap() >= end);
+
+        let passed = Timer::until(glib::DateTime::now_local().unwrap(), |_| {});
+        assert!(passed.now_or_never().unwrap().is_ok());
+    }
+
     #[gtk::test]
     fn zero_duration() {
         let control = Timer::new(Duration::from_nanos(10), |_| {});
@@ -0,0 +1,421 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, ensure};
+use gst::prelude::*;
+
+use crate::{
+    pipeline::caps_size,
+    settings::{WebcamPosition, WebcamShape},
+};
+
+/// Height of the webcam frames before they are scaled onto the screen.
+const FRAME_HEIGHT: i32 = 360;
+
+/// Gap between the webcam and the edges of the screen, relative to the
+/// screen height.
+const MARGIN: f64 = 0.03;
+
+/// A webcam drawn on top of the screen.
+#[derive(Debug, Clone)]
+pub struct WebcamOverlay {
+    src: gst::Element,
+    position: WebcamPosition,
+    scale: f64,
+    shape: WebcamShape,
+}
+
+impl WebcamOverlay {
+    /// The scale is the width of the webcam relative to the screen width.
+    pub fn new(
+        src: gst::Element,
+        position: WebcamPosition,
+        scale: f64,
+        shape: WebcamShape,
+    ) -> Self {
+        Self {
+            src,
+            position,
+            scale,
+            shape,
+        }
+    }
+
+    /// Creates a bin that draws the webcam on top of the video from its
+    /// sink pad.
+    ///
+    ///                                                  sink -> |
+    ///                                                          | -> compositor -> src
+    /// webcamsrc -> videoconvert -> aspectratiocrop -> videoscale -> videoconvert -> capsfilter -> |
+    ///
+    /// The webcam is placed once the size of the screen is known, and, if it
+    /// is a circle, everything outside of it is made transparent.
+    pub fn make_bin(&self) -> Result<gst::Bin> {
+        let bin = gst::Bin::builder().name("kooha-webcam-overlay-bin").build();
+
+        let aspect_ratio = shape_aspect_ratio(self.shape);
+
+        let aspectratiocrop = gst::ElementFactory::make("aspectratiocrop")
+            .property("aspect-ratio", aspect_ratio)
+            .build()?;
+        let capsfilter = gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("format", "BGRA")
+                    .field("height", FRAME_HEIGHT)
+                    .field("pixel-aspect-ratio", gst::Fraction::from_integer(1))
+                    .build(),
+            )
+            .build()?;
+        let compositor = gst::ElementFactory::make("compositor").build()?;
+
+        let webcam_elements = [
+            self.src.clone(),
+            gst::ElementFactory::make("videoconvert").build()?,
+            aspectratiocrop,
+            gst::ElementFactory::make("videoscale").build()?,
+            gst::ElementFactory::make("videoconvert").build()?,
+            capsfilter.clone(),
+        ];
+        bin.add_many(&webcam_elements)?;
+        bin.add(&compositor)?;
+        gst::Element::link_many(&webcam_elements)?;
+
+        let screen_pad = compositor
+            .request_pad_simple("sink_%u")
+            .context("Failed to request sink_%u pad from compositor")?;
+        screen_pad.set_property("zorder", 0_u32);
+
+        let webcam_pad = compositor
+            .request_pad_simple("sink_%u")
+            .context("Failed to request sink_%u pad from compositor")?;
+        webcam_pad.set_property("zorder", 1_u32);
+        capsfilter.static_pad("src").unwrap().link(&webcam_pad)?;
+
+        if self.shape == WebcamShape::Circle {
+            add_circle_mask_probe(&capsfilter.static_pad("src").unwrap());
+        }
+
+        let (position, scale) = (self.position, self.scale);
+        screen_pad.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(event) = info.event()
+                && let gst::EventView::Caps(caps) = event.view()
+            {
+                match caps_size(caps.caps()) {
+                    Ok(screen_size) => {
+                        let (x, y, width, height) =
+                            webcam_rect(screen_size, aspect_ratio, position, scale);
+
+                        tracing::debug!(x, y, width, height, "Placing webcam");
+
+                        webcam_pad.set_property("xpos", x);
+                        webcam_pad.set_property("ypos", y);
+                        webcam_pad.set_property("width", width);
+                        webcam_pad.set_property("height", height);
+                    }
+                    Err(err) => tracing::warn!("Failed to get screen size: {:?}", err),
+                }
+            }
+
+            gst::PadProbeReturn::Ok
+        });
+
+        bin.add_pad(&gst::GhostPad::with_target(&screen_pad)?)?;
+
+        let src_pad = compositor.static_pad("src").unwrap();
+        bin.add_pad(&gst::GhostPad::with_target(&src_pad)?)?;
+
+        Ok(bin)
+    }
+}
+
+/// Returns the cameras.
+///
+/// The same camera may be listed by multiple device providers,
+/// so only the first one with a given name is kept.
+pub fn all() -> Result<Vec<gst::Device>> {
+    let monitor = gst::DeviceMonitor::new();
+    monitor.add_filter(Some("Video/Source"), None);
+    monitor.start()?;
+    let devices = monitor.devices();
+    monitor.stop();
+
+    let mut ret = Vec::<gst::Device>::new();
+    for device in devices {
+        if ret.iter().all(|other| id(other) != id(&device)) {
+            ret.push(device);
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Returns the identifier of the camera.
+///
+/// This is the display name, followed by where the camera is plugged in if it
+/// is known, so that identical cameras can be told apart. Both are shared by
+/// the providers and stay the same across restarts.
+pub fn id(device: &gst::Device) -> String {
+    let name = device.display_name();
+
+    match bus_info(device) {
+        Some(bus_info) => format!("{} ({})", name, bus_info),
+        None => name.into(),
+    }
+}
+
+/// Returns where the camera is plugged in, e.g., `usb-0000:00:14.0-1`.
+fn bus_info(device: &gst::Device) -> Option<String> {
+    let properties = device.properties()?;
+
+    // Set by the V4L2 and the PipeWire providers, respectively.
+    ["v4l2.device.bus_info", "api.v4l2.cap.bus_info"]
+        .into_iter()
+        .find_map(|key| properties.get::<String>(key).ok())
+        .filter(|bus_info| !bus_info.is_empty())
+}
+
+/// Creates a src element for the camera with the given id, or for the
+/// first camera if the id is empty.
+///
+/// Returns `None` if there is no such camera.
+pub fn make_src(id: &str) -> Result<Option<gst::Element>> {
+    let device = all()?
+        .into_iter()
+        .find(|device| id.is_empty() || self::id(device) == id);
+
+    let Some(device) = device else {
+        return Ok(None);
+    };
+
+    tracing::debug!("Found webcam with name `{}`", self::id(&device));
+
+    let src = device.create_element(Some("kooha-webcam-src"))?;
+    Ok(Some(src))
+}
+
+fn shape_aspect_ratio(shape: WebcamShape) -> gst::Fraction {
+    match shape {
+        WebcamShape::Rectangle => gst::Fraction::new(4, 3),
+        WebcamShape::Circle => gst::Fraction::from_integer(1),
+    }
+}
+
+/// Returns the `(x, y, width, height)` of the webcam on a screen of the
+/// given size.
+fn webcam_rect(
+    screen_size: (i32, i32),
+    aspect_ratio: gst::Fraction,
+    position: WebcamPosition,
+    scale: f64,
+) -> (i32, i32, i32, i32) {
+    let (screen_width, screen_height) = screen_size;
+    let aspect_ratio = aspect_ratio.numer() as f64 / aspect_ratio.denom() as f64;
+
+    let margin = (screen_height as f64 * MARGIN).round() as i32;
+    let width = ((screen_width as f64 * scale).round() as i32).clamp(1, screen_width.max(1));
+    let height = ((width as f64 / aspect_ratio).round() as i32).clamp(1, screen_height.max(1));
+
+    let x = match position {
+        WebcamPosition::TopLeft | WebcamPosition::BottomLeft => margin,
+        WebcamPosition::TopRight | WebcamPosition::BottomRight => screen_width - width - margin,
+    };
+    let y = match position {
+        WebcamPosition::TopLeft | WebcamPosition::TopRight => margin,
+        WebcamPosition::BottomLeft | WebcamPosition::BottomRight => screen_height - height - margin,
+    };
+
+    (x.max(0), y.max(0), width, height)
+}
+
+/// Makes everything outside of the biggest circle that fits the frames
+/// transparent. The frames must be BGRA.
+fn add_circle_mask_probe(pad: &gst::Pad) {
+    let mask = Arc::new(Mutex::new(None::<CircleMask>));
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let Some(caps) = pad.current_caps() else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let Some(buffer) = info.buffer_mut() else {
+            return gst::PadProbeReturn::Ok;
+        };
+
+        let res = caps_size(&caps).and_then(|(width, height)| {
+            let mut mask = mask.lock().unwrap();
+            let mask = match *mask {
+                Some(ref mask) if mask.width == width && mask.height == height => mask,
+                _ => mask.insert(CircleMask::new(width, height)),
+            };
+            mask.apply(buffer.make_mut())
+        });
+
+        if let Err(err) = res {
+            tracing::warn!("Failed to mask webcam frame: {:?}", err);
+        }
+
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Alpha of each pixel, with antialiased edges.
+#[derive(Debug)]
+struct CircleMask {
+    width: i32,
+    height: i32,
+    alphas: Vec<u8>,
+}
+
+impl CircleMask {
+    fn new(width: i32, height: i32) -> Self {
+        let radius = width.min(height) as f64 / 2.0;
+        let (center_x, center_y) = (width as f64 / 2.0, height as f64 / 2.0);
+
+        let mut alphas = Vec::with_capacity((width * height).max(0) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let distance = (x as f64 + 0.5 - center_x).hypot(y as f64 + 0.5 - center_y);
+                let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+                alphas.push((coverage * 255.0).round() as u8);
+            }
+        }
+
+        Self {
+            width,
+            height,
+            alphas,
+        }
+    }
+
+    fn apply(&self, buffer: &mut gst::BufferRef) -> Result<()> {
+        let mut map = buffer.map_writable()?;
+        ensure!(
+            map.len() >= self.alphas.len() * 4,
+            "Frame is smaller than the mask"
+        );
+
+        for (pixel, alpha) in map.chunks_exact_mut(4).zip(&self.alphas) {
+            pixel[3] = (pixel[3] as u16 * *alpha as u16 / 255) as u8;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rect_in_corners() {
+        let square = gst::Fraction::from_integer(1);
+
+        assert_eq!(
+            webcam_rect((1000, 500), square, WebcamPosition::TopLeft, 0.2),
+            (15, 15, 200, 200)
+        );
+        assert_eq!(
+            webcam_rect((1000, 500), square, WebcamPosition::TopRight, 0.2),
+            (785, 15, 200, 200)
+        );
+        assert_eq!(
+            webcam_rect((1000, 500), square, WebcamPosition::BottomLeft, 0.2),
+            (15, 285, 200, 200)
+        );
+        assert_eq!(
+            webcam_rect(
+                (1000, 500),
+                gst::Fraction::new(4, 3),
+                WebcamPosition::BottomRight,
+                0.2
+            ),
+            (785, 335, 200, 150)
+        );
+    }
+
+    #[test]
+    fn rect_fits_screen() {
+        let (x, y, width, height) = webcam_rect(
+            (400, 100),
+            gst::Fraction::from_integer(1),
+            WebcamPosition::BottomRight,
+            1.0,
+        );
+
+        assert!(x >= 0 && y >= 0);
+        assert!(width <= 400 && height <= 100);
+    }
+
+    #[test]
+    fn circle_mask() {
+        let mask = CircleMask::new(10, 10);
+        assert_eq!(mask.alphas.len(), 100);
+        // Corners are outside, center is inside.
+        assert_eq!(mask.alphas[0], 0);
+        assert_eq!(mask.alphas[99], 0);
+        assert_eq!(mask.alphas[55], 255);
+    }
+
+    #[test]
+    fn overlay_bin() {
+        gst::init().unwrap();
+
+        let pipeline = gst::Pipeline::new();
+        let screen_src = gst::ElementFactory::make("videotestsrc")
+            .property("num-buffers", 3)
+            .build()
+            .unwrap();
+        let screen_capsfilter = gst::ElementFactory::make("capsfilter")
+            .property(
+                "caps",
+                gst::Caps::builder("video/x-raw")
+                    .field("width", 640)
+                    .field("height", 480)
+                    .build(),
+            )
+            .build()
+            .unwrap();
+        let webcam_src = gst::ElementFactory::make("videotestsrc")
+            .property("num-buffers", 3)
+            .build()
+            .unwrap();
+        let sink = gst::ElementFactory::make("fakesink").build().unwrap();
+
+        let overlay_bin = WebcamOverlay::new(
+            webcam_src,
+            WebcamPosition::BottomRight,
+            0.25,
+            WebcamShape::Circle,
+        )
+        .make_bin()
+        .unwrap();
+
+        pipeline
+            .add_many([
+                &screen_src,
+                &screen_capsfilter,
+                overlay_bin.upcast_ref(),
+                &sink,
+            ])
+            .unwrap();
+        gst::Element::link_many([
+            &screen_src,
+            &screen_capsfilter,
+            overlay_bin.upcast_ref(),
+            &sink,
+        ])
+        .unwrap();
+
+        pipeline.set_state(gst::State::Playing).unwrap();
+        let message = pipeline
+            .bus()
+            .unwrap()
+            .timed_pop_filtered(
+                gst::ClockTime::from_seconds(10),
+                &[gst::MessageType::Eos, gst::MessageType::Error],
+            )
+            .unwrap();
+        pipeline.set_state(gst::State::Null).unwrap();
+
+        assert_eq!(message.type_(), gst::MessageType::Eos);
+    }
+}
@@ -2,14 +2,14 @@ mod progress_icon;
 mod toggle_button;
 
 use adw::{prelude::*, subclass::prelude::*};
-use anyhow::{Error, Result};
+use anyhow::{Context, Error, Result, ensure};
 use gettextrs::gettext;
 use gtk::{
     gio,
     glib::{self, clone},
 };
 
-use std::cell::RefCell;
+use std::{cell::RefCell, slice};
 
 use self::{progress_icon::ProgressIcon, toggle_button::ToggleButton};
 use crate::{
@@ -18,9 +18,13 @@ use crate::{
     config::PROFILE,
     format,
     help::ContextWithHelp,
+    i18n::gettext_f,
+    post_recording_hook,
     preferences_dialog::PreferencesDialog,
     recording::{NoProfileError, Recording, RecordingState},
+    schedule::Schedule,
     settings::CaptureMode,
+    timer::Timer,
 };
 
 mod imp {
@@ -29,6 +33,8 @@ mod imp {
     #[derive(Debug, Default, gtk::CompositeTemplate)]
     #[template(resource = "/io/github/seadve/Kooha/ui/window.ui")]
     pub struct Window {
+        #[template_child]
+        pub(super) toast_overlay: TemplateChild<adw::ToastOverlay>,
         #[template_child]
         pub(super) title: TemplateChild<adw::WindowTitle>,
         #[template_child]
@@ -38,12 +44,30 @@ mod imp {
         #[template_child]
         pub(super) forget_video_sources_revealer: TemplateChild<gtk::Revealer>,
         #[template_child]
+        pub(super) schedules_list: TemplateChild<gtk::ListBox>,
+        #[template_child]
         pub(super) recording_page: TemplateChild<gtk::Box>,
         #[template_child]
         pub(super) recording_label: TemplateChild<gtk::Label>,
         #[template_child]
         pub(super) recording_time_label: TemplateChild<gtk::Label>,
         #[template_child]
+        pub(super) stop_limits_label: TemplateChild<gtk::Label>,
+        #[template_child]
+        pub(super) disk_space_label: TemplateChild<gtk::Label>,
+        #[template_child]
+        pub(super) desktop_audio_level_box: TemplateChild<gtk::Box>,
+        #[template_child]
+        pub(super) desktop_audio_level_bar: TemplateChild<gtk::LevelBar>,
+        #[template_child]
+        pub(super) microphone_level_box: TemplateChild<gtk::Box>,
+        #[template_child]
+        pub(super) microphone_level_bar: TemplateChild<gtk::LevelBar>,
+        #[template_child]
+        pub(super) text_overlay_entry: TemplateChild<gtk::Entry>,
+        #[template_child]
+        pub(super) save_replay_button: TemplateChild<gtk::Button>,
+        #[template_child]
         pub(super) pause_record_button: TemplateChild<gtk::Button>,
         #[template_child]
         pub(super) delay_page: TemplateChild<gtk::Box>,
@@ -56,6 +80,10 @@ mod imp {
 
         pub(super) inhibit_cookie: RefCell<Option<u32>>,
         pub(super) recording: RefCell<Option<(Recording, Vec<glib::SignalHandlerId>)>>,
+        /// Schedules waiting for their start, in the order they were added
+        pub(super) schedules: RefCell<Vec<(Schedule, Timer)>>,
+        /// Stops the recording that was started by a schedule
+        pub(super) scheduled_stop_timer: RefCell<Option<Timer>>,
     }
 
     #[glib::object_subclass]
@@ -69,8 +97,10 @@ mod imp {
 
             klass.bind_template();
 
-            klass.install_action_async("win.toggle-record", None, |obj, _, _| async move {
-                obj.toggle_record().await;
+            klass.install_action("win.toggle-record", None, |obj, _, _| {
+                if let Err(err) = obj.toggle_record() {
+                    tracing::error!("Failed to toggle record: {:?}", err);
+                }
             });
 
             klass.install_action("win.toggle-pause", None, move |obj, _, _| {
@@ -81,8 +111,21 @@ mod imp {
                 }
             });
 
+            klass.install_action_async("win.save-replay", None, |obj, _, _| async move {
+                if let Err(err) = obj.save_replay().await {
+                    tracing::error!("{:?}", err);
+                    obj.present_recording_error_dialog(&err);
+                }
+            });
+
             klass.install_action("win.cancel-record", None, move |obj, _, _| {
-                obj.cancel_record();
+                if let Err(err) = obj.cancel_record() {
+                    tracing::error!("Failed to cancel record: {:?}", err);
+                }
+            });
+
+            klass.install_action_async("win.schedule-record", None, |obj, _, _| async move {
+                obj.present_schedule_dialog().await;
             });
 
             klass.install_action("win.forget-video-sources", None, move |_obj, _, _| {
@@ -331,13 +374,16 @@ impl Window {
         dialog.present(Some(self));
     }
 
-    async fn toggle_record(&self) {
+    /// Starts a new recording in the background.
+    ///
+    /// This fails if there is already an ongoing recording.
+    pub fn start_record(&self) -> Result<()> {
         let imp = self.imp();
 
-        if let Some((ref recording, _)) = *imp.recording.borrow() {
-            recording.stop();
-            return;
-        }
+        ensure!(
+            imp.recording.borrow().is_none(),
+            "A recording is already in progress"
+        );
 
         let recording = Recording::new();
         let handler_ids = vec![
@@ -355,6 +401,44 @@ impl Window {
                 move |recording| {
                     let formatted_time = format::digital_clock(recording.duration());
                     obj.imp().recording_time_label.set_label(&formatted_time);
+                    obj.update_stop_limits_label(recording);
+                    obj.update_disk_space_label(recording);
+                }
+            )),
+            recording.connect_size_notify(clone!(
+                #[weak(rename_to = obj)]
+                self,
+                move |recording| {
+                    obj.update_stop_limits_label(recording);
+                }
+            )),
+            // The meters are only shown once there are levels, as not all
+            // sources are recorded.
+            recording.connect_desktop_audio_level_notify(clone!(
+                #[weak(rename_to = obj)]
+                self,
+                move |recording| {
+                    let imp = obj.imp();
+                    imp.desktop_audio_level_bar
+                        .set_value(recording.desktop_audio_level());
+                    imp.desktop_audio_level_box.set_visible(true);
+                }
+            )),
+            recording.connect_microphone_level_notify(clone!(
+                #[weak(rename_to = obj)]
+                self,
+                move |recording| {
+                    let imp = obj.imp();
+                    imp.microphone_level_bar
+                        .set_value(recording.microphone_level());
+                    imp.microphone_level_box.set_visible(true);
+                }
+            )),
+            recording.connect_warning(clone!(
+                #[weak(rename_to = obj)]
+                self,
+                move |_, message| {
+                    obj.imp().toast_overlay.add_toast(adw::Toast::new(message));
                 }
             )),
             recording.connect_finished(clone!(
@@ -368,57 +452,319 @@ impl Window {
         imp.recording
             .replace(Some((recording.clone(), handler_ids)));
 
+        let app = Application::get();
+
+        if let Some(dbus_service) = app.dbus_service() {
+            dbus_service.watch_recording(&recording);
+        }
+
         self.update_inhibit();
 
-        recording
-            .start(Some(self), Application::get().settings())
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to = obj)]
+            self,
+            async move {
+                recording
+                    .start(Some(&obj), Application::get().settings())
+                    .await;
+            }
+        ));
+
+        Ok(())
+    }
+
+    pub fn stop_record(&self) -> Result<()> {
+        let recording = self.recording().context("No ongoing recording to stop")?;
+        recording.stop();
+
+        Ok(())
+    }
+
+    pub fn pause_record(&self) -> Result<()> {
+        let recording = self.recording().context("No ongoing recording to pause")?;
+        recording.pause()
+    }
+
+    pub fn resume_record(&self) -> Result<()> {
+        let recording = self.recording().context("No ongoing recording to resume")?;
+        recording.resume()
+    }
+
+    pub fn cancel_record(&self) -> Result<()> {
+        let recording = self.recording().context("No ongoing recording to cancel")?;
+        recording.cancel();
+
+        Ok(())
+    }
+
+    /// Saves the replay buffer of the ongoing recording to a file.
+    pub async fn save_replay(&self) -> Result<gio::File> {
+        let recording = self
+            .recording()
+            .context("No ongoing recording to save replay from")?;
+
+        let app = Application::get();
+        let (file, duration) = recording.save_replay(app.settings()).await?;
+
+        let recent_manager = gtk::RecentManager::default();
+        recent_manager.add_item(&file.uri());
+
+        spawn_post_recording_command(&recording, vec![file.clone()], duration);
+
+        app.send_record_success_notification(slice::from_ref(&file), duration)
             .await;
+
+        Ok(file)
     }
 
-    fn toggle_pause(&self) -> Result<()> {
+    async fn present_schedule_dialog(&self) {
+        const CANCEL_RESPONSE_ID: &str = "cancel";
+        const SCHEDULE_RESPONSE_ID: &str = "schedule";
+
+        let start_row = adw::EntryRow::builder()
+            .title(gettext("Start Time"))
+            .build();
+        let stop_row = adw::EntryRow::builder()
+            .title(gettext("Stop Time (Optional)"))
+            .build();
+
+        let list_box = gtk::ListBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .build();
+        list_box.add_css_class("boxed-list");
+        list_box.append(&start_row);
+        list_box.append(&stop_row);
+
+        let has_restore_token = !Application::get()
+            .settings()
+            .screencast_restore_token()
+            .is_empty();
+        let body = if has_restore_token {
+            gettext("Enter times as HH:MM, or as YYYY-MM-DD HH:MM for another day.")
+        } else {
+            gettext(
+                "Enter times as HH:MM, or as YYYY-MM-DD HH:MM for another day. Record once beforehand to remember the video sources, or they will be asked for when it starts.",
+            )
+        };
+
+        let dialog = adw::AlertDialog::builder()
+            .heading(gettext("Schedule Recording"))
+            .body(body)
+            .close_response(CANCEL_RESPONSE_ID)
+            .default_response(SCHEDULE_RESPONSE_ID)
+            .extra_child(&list_box)
+            .build();
+        dialog.add_response(CANCEL_RESPONSE_ID, &gettext("Cancel"));
+
+        dialog.add_response(SCHEDULE_RESPONSE_ID, &gettext("Schedule"));
+        dialog.set_response_appearance(SCHEDULE_RESPONSE_ID, adw::ResponseAppearance::Suggested);
+        dialog.set_response_enabled(SCHEDULE_RESPONSE_ID, false);
+
+        for row in [&start_row, &stop_row] {
+            row.connect_changed(clone!(
+                #[weak]
+                dialog,
+                #[weak]
+                start_row,
+                #[weak]
+                stop_row,
+                move |_| {
+                    dialog.set_response_enabled(
+                        SCHEDULE_RESPONSE_ID,
+                        schedule_from_rows(&start_row, &stop_row).is_ok(),
+                    );
+                }
+            ));
+        }
+
+        if dialog.choose_future(Some(self)).await != SCHEDULE_RESPONSE_ID {
+            return;
+        }
+
+        match schedule_from_rows(&start_row, &stop_row) {
+            Ok(schedule) => self.add_schedule(schedule),
+            Err(err) => {
+                let err = err.context(gettext("Failed to schedule recording"));
+                tracing::error!("{:?}", err);
+                self.present_recording_error_dialog(&err);
+            }
+        }
+    }
+
+    /// Starts a recording at the start of the schedule, unless it is
+    /// cancelled from the list of schedules before that.
+    fn add_schedule(&self, schedule: Schedule) {
         let imp = self.imp();
 
-        if let Some((ref recording, _)) = *imp.recording.borrow() {
-            if matches!(recording.state(), RecordingState::Paused) {
-                recording.resume()?;
-            } else {
-                recording.pause()?;
-            };
+        tracing::debug!(?schedule, "Adding schedule");
+
+        let timer = Timer::until(schedule.start.clone(), |_| {});
+        imp.schedules
+            .borrow_mut()
+            .push((schedule.clone(), timer.clone()));
+        self.update_schedules_list();
+
+        glib::spawn_future_local(clone!(
+            #[weak(rename_to = obj)]
+            self,
+            async move {
+                let res = timer.clone().await;
+
+                obj.imp()
+                    .schedules
+                    .borrow_mut()
+                    .retain(|(_, other)| !other.ptr_eq(&timer));
+                obj.update_schedules_list();
+
+                if let Err(err) = res {
+                    tracing::debug!("{:?}", err);
+                    return;
+                }
+
+                if let Err(err) = obj.start_scheduled_record(&schedule) {
+                    let err = err.context(gettext("Failed to start scheduled recording"));
+                    tracing::error!("{:?}", err);
+                    obj.present_recording_error_dialog(&err);
+                }
+            }
+        ));
+    }
+
+    fn start_scheduled_record(&self, schedule: &Schedule) -> Result<()> {
+        self.start_record()?;
+
+        if let Some(ref stop) = schedule.stop {
+            let recording = self
+                .recording()
+                .context("Started recording was not stored")?;
+
+            let timer = Timer::until(stop.clone(), |_| {});
+            self.imp().scheduled_stop_timer.replace(Some(timer.clone()));
+
+            glib::spawn_future_local(async move {
+                if timer.await.is_err() {
+                    return;
+                }
+
+                match recording.state() {
+                    RecordingState::Recording | RecordingState::Paused => recording.stop(),
+                    RecordingState::Delayed { .. } => recording.cancel(),
+                    _ => {}
+                }
+            });
         }
 
         Ok(())
     }
 
-    fn cancel_record(&self) {
+    fn cancel_schedule(&self, index: usize) {
+        if let Some((schedule, timer)) = self.imp().schedules.borrow().get(index) {
+            tracing::debug!(?schedule, "Cancelling schedule");
+
+            // It is removed from the list once the timer is done.
+            timer.cancel();
+        }
+    }
+
+    fn update_schedules_list(&self) {
         let imp = self.imp();
 
-        if let Some((ref recording, _)) = *imp.recording.borrow() {
-            recording.cancel();
+        imp.schedules_list.remove_all();
+
+        let schedules = imp.schedules.borrow();
+        for (index, (schedule, _)) in schedules.iter().enumerate() {
+            let row = adw::ActionRow::builder()
+                .title(format::date_time(&schedule.start))
+                .build();
+
+            if let Some(ref stop) = schedule.stop {
+                row.set_subtitle(&gettext_f(
+                    // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                    "Until {time}",
+                    &[("time", &format::date_time(stop))],
+                ));
+            }
+
+            // Timers are cancelled when dropped, so the button must not
+            // hold one.
+            let cancel_button = gtk::Button::builder()
+                .valign(gtk::Align::Center)
+                .tooltip_text(gettext("Cancel Schedule"))
+                .icon_name("window-close-symbolic")
+                .build();
+            cancel_button.add_css_class("flat");
+            cancel_button.connect_clicked(clone!(
+                #[weak(rename_to = obj)]
+                self,
+                move |_| {
+                    obj.cancel_schedule(index);
+                }
+            ));
+            row.add_suffix(&cancel_button);
+
+            imp.schedules_list.append(&row);
+        }
+
+        imp.schedules_list.set_visible(!schedules.is_empty());
+    }
+
+    fn recording(&self) -> Option<Recording> {
+        self.imp()
+            .recording
+            .borrow()
+            .as_ref()
+            .map(|(recording, _)| recording.clone())
+    }
+
+    fn toggle_record(&self) -> Result<()> {
+        if self.recording().is_some() {
+            self.stop_record()
+        } else {
+            self.start_record()
         }
     }
 
+    fn toggle_pause(&self) -> Result<()> {
+        if let Some(recording) = self.recording() {
+            if matches!(recording.state(), RecordingState::Paused) {
+                recording.resume()?;
+            } else {
+                recording.pause()?;
+            };
+        }
+
+        Ok(())
+    }
+
     fn handle_recording_finished(
         &self,
         recording: &Recording,
-        res: &Result<(gio::File, gst::ClockTime)>,
+        res: &Result<(Vec<gio::File>, gst::ClockTime)>,
     ) {
         debug_assert_eq!(recording.state(), RecordingState::Finished);
 
         match res {
-            Ok((recording_file, duration)) => {
+            // Replay buffers have no files of their own, as each replay is
+            // notified as it is saved.
+            Ok((recording_files, _)) if recording_files.is_empty() => {}
+            Ok((recording_files, duration)) => {
                 let duration = *duration;
                 glib::spawn_future_local(clone!(
                     #[strong]
-                    recording_file,
+                    recording_files,
                     async move {
                         let app = Application::get();
-                        app.send_record_success_notification(&recording_file, duration)
+                        app.send_record_success_notification(&recording_files, duration)
                             .await;
                     }
                 ));
 
+                spawn_post_recording_command(recording, recording_files.clone(), duration);
+
                 let recent_manager = gtk::RecentManager::default();
-                recent_manager.add_item(&recording_file.uri());
+                for recording_file in recording_files {
+                    recent_manager.add_item(&recording_file.uri());
+                }
             }
             Err(err) => {
                 if err.is::<Cancelled>() {
@@ -437,6 +783,10 @@ impl Window {
             }
         }
 
+        if let Some(timer) = self.imp().scheduled_stop_timer.take() {
+            timer.cancel();
+        }
+
         if let Some((recording, handler_ids)) = self.imp().recording.take() {
             for handler_id in handler_ids {
                 recording.disconnect(handler_id);
@@ -470,16 +820,90 @@ impl Window {
         }
     }
 
+    /// Shows what is left until the recording stops on its own, if it has
+    /// stop limits.
+    fn update_stop_limits_label(&self, recording: &Recording) {
+        let imp = self.imp();
+
+        let stop_limits = recording.stop_limits();
+        let remaining = [
+            stop_limits.max_duration.map(|max_duration| {
+                gettext_f(
+                    // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                    "{time} left",
+                    &[(
+                        "time",
+                        &format::digital_clock(max_duration.saturating_sub(recording.duration())),
+                    )],
+                )
+            }),
+            stop_limits.max_size.map(|max_size| {
+                gettext_f(
+                    // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+                    "{size} left",
+                    &[(
+                        "size",
+                        &glib::format_size(max_size.saturating_sub(recording.size())),
+                    )],
+                )
+            }),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        imp.stop_limits_label.set_label(&remaining.join(" · "));
+        imp.stop_limits_label.set_visible(!remaining.is_empty());
+    }
+
+    /// Shows how long it can still record until the disk is full, which
+    /// stands out once it is running out.
+    fn update_disk_space_label(&self, recording: &Recording) {
+        let imp = self.imp();
+
+        let Some(time_left) = recording.disk_space_time_left() else {
+            imp.disk_space_label.set_visible(false);
+            return;
+        };
+
+        imp.disk_space_label.set_label(&gettext_f(
+            // Translators: Do NOT translate the contents between '{' and '}', this is a variable name.
+            "{time} of disk space left",
+            &[("time", &format::elapsed_time(time_left))],
+        ));
+
+        if recording.is_low_on_disk_space() {
+            imp.disk_space_label.remove_css_class("dimmed");
+            imp.disk_space_label.add_css_class("warning");
+        } else {
+            imp.disk_space_label.remove_css_class("warning");
+            imp.disk_space_label.add_css_class("dimmed");
+        }
+
+        imp.disk_space_label.set_visible(true);
+    }
+
     fn update_view(&self) {
         let imp = self.imp();
 
         // TODO disregard ms granularity recording state change
 
-        let state = imp
-            .recording
-            .borrow()
-            .as_ref()
-            .map_or(RecordingState::Init, |(recording, _)| recording.state());
+        let (state, is_replay_buffer, has_text_overlay) = imp.recording.borrow().as_ref().map_or(
+            (RecordingState::Init, false, false),
+            |(recording, _)| {
+                (
+                    recording.state(),
+                    recording.is_replay_buffer(),
+                    recording.has_text_overlay(),
+                )
+            },
+        );
+
+        let recording_label = if is_replay_buffer {
+            gettext("Replay Buffer")
+        } else {
+            gettext("Recording")
+        };
 
         match state {
             RecordingState::Init | RecordingState::Finished => {
@@ -487,6 +911,11 @@ impl Window {
 
                 imp.recording_time_label
                     .set_label(&format::digital_clock(gst::ClockTime::ZERO));
+
+                imp.stop_limits_label.set_visible(false);
+                imp.disk_space_label.set_visible(false);
+                imp.desktop_audio_level_box.set_visible(false);
+                imp.microphone_level_box.set_visible(false);
             }
             RecordingState::Delayed { secs_left } => {
                 imp.delay_label.set_label(&secs_left.to_string());
@@ -496,7 +925,7 @@ impl Window {
             RecordingState::Recording => {
                 imp.pause_record_button
                     .set_icon_name("media-playback-pause-symbolic");
-                imp.recording_label.set_label(&gettext("Recording"));
+                imp.recording_label.set_label(&recording_label);
                 imp.recording_time_label.remove_css_class("paused");
 
                 imp.stack.set_visible_child(&*imp.recording_page);
@@ -524,6 +953,13 @@ impl Window {
                 RecordingState::Delayed { .. } | RecordingState::Flushing { .. }
             ),
         );
+        imp.save_replay_button.set_visible(is_replay_buffer);
+        imp.text_overlay_entry.set_visible(has_text_overlay);
+
+        self.action_set_enabled(
+            "win.save-replay",
+            is_replay_buffer && matches!(state, RecordingState::Recording | RecordingState::Paused),
+        );
         self.action_set_enabled(
             "win.toggle-pause",
             matches!(state, RecordingState::Recording | RecordingState::Paused),
@@ -613,6 +1049,19 @@ impl Window {
             }
         ));
 
+        settings.connect_text_overlay_text_changed(clone!(
+            #[weak(rename_to = obj)]
+            self,
+            move |settings| {
+                if let Some((ref recording, _)) = *obj.imp().recording.borrow() {
+                    recording.set_overlay_text(&settings.text_overlay_text());
+                }
+            }
+        ));
+        settings
+            .bind_text_overlay_text(&self.imp().text_overlay_entry.get(), "text")
+            .build();
+
         settings.connect_screencast_restore_token_changed(clone!(
             #[weak(rename_to = obj)]
             self,
@@ -625,5 +1074,43 @@ impl Window {
         self.add_action(&settings.create_record_microphone_action());
         self.add_action(&settings.create_show_pointer_action());
         self.add_action(&settings.create_capture_mode_action());
+        self.add_action(&settings.create_replay_buffer_action());
     }
 }
+
+/// Returns the schedule entered in the rows, where an empty stop row means
+/// that there is no stop time.
+fn schedule_from_rows(start_row: &adw::EntryRow, stop_row: &adw::EntryRow) -> Result<Schedule> {
+    let now = glib::DateTime::now_local().expect("You are somehow on year 9999");
+    let stop = stop_row.text();
+    Schedule::parse(
+        &start_row.text(),
+        Some(stop.trim()).filter(|stop| !stop.is_empty()),
+        &now,
+    )
+}
+
+/// Runs the post-recording command on the files in the background, if one is
+/// set, notifying if it fails.
+fn spawn_post_recording_command(
+    recording: &Recording,
+    files: Vec<gio::File>,
+    duration: gst::ClockTime,
+) {
+    let command = Application::get().settings().post_recording_command();
+    if command.trim().is_empty() {
+        return;
+    }
+
+    let profile_id = recording
+        .profile()
+        .map(|profile| profile.id().to_string())
+        .unwrap_or_default();
+    glib::spawn_future_local(async move {
+        if let Err(err) = post_recording_hook::run(&command, &files, duration, &profile_id).await {
+            tracing::error!("Failed to run post-recording command: {:?}", err);
+
+            Application::get().send_post_recording_command_failure_notification(&err);
+        }
+    });
+}